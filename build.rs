@@ -0,0 +1,17 @@
+use std::process::Command;
+
+//Record the commit being built, so mismatched clients and servers can be told apart
+fn main() {
+    let build_id = Command::new("git")
+        .args(["describe", "--always", "--dirty"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|| String::from("unknown"));
+
+    println!("cargo:rustc-env=MACROPROX_BUILD_ID={}", build_id);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+}
@@ -0,0 +1,87 @@
+//Measures snapshot sizes with 64 players moving around, compared to sending every moved player in full
+//Run with: cargo run --example snapshot_bytes --no-default-features
+
+use macroprox::net_common::{Commands, NetPosition, PositionMap, Snapshot};
+use macroprox::snapshot::{self, SnapshotReceiver, SnapshotSender, SnapshotState};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const PLAYERS: usize = 64;
+const TICKS: u32 = 1000;
+//Distance walked in one 15ms update
+const STEP: f32 = 250.0 * 0.015;
+//Chance of a player moving in a tick, most players stand still a lot of the time
+const MOVE_CHANCE: f64 = 0.5;
+//Chance of a snapshot being lost on the way to the client
+const LOSS: f64 = 0.1;
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut positions: Vec<NetPosition> = (0..PLAYERS)
+        .map(|_| NetPosition { x: rng.gen_range(0.0..2000.0), y: rng.gen_range(0.0..2000.0) })
+        .collect();
+    let mut last = positions.clone();
+
+    let mut sender = SnapshotSender::new();
+    let mut receiver = SnapshotReceiver::default();
+    let mut client_view = SnapshotState::new();
+    let mut old_bytes = 0;
+    let mut new_bytes = 0;
+
+    for tick in 1..=TICKS {
+        for p in positions.iter_mut() {
+            if rng.gen_bool(MOVE_CHANCE) {
+                let angle: f32 = rng.gen_range(0.0..std::f32::consts::TAU);
+                p.x += angle.cos() * STEP;
+                p.y += angle.sin() * STEP;
+            }
+        }
+        let time = tick as u64 * 15;
+
+        //Old format, every other player that moved since the last update, with full floats
+        let moved: Vec<PositionMap> = (1..PLAYERS)
+            .filter(|i| !positions[*i].equals(&last[*i]))
+            .map(|i| PositionMap { id: i as u8, pos: positions[i] })
+            .collect();
+        old_bytes += bincode::serialize(&(4u32, time, moved)).unwrap().len();
+        last = positions.clone();
+
+        //New format, as seen by one client that knows about everyone else
+        let state: SnapshotState = (1..PLAYERS).map(|i| (i as u8, snapshot::quantise(positions[i]))).collect();
+        let (seq, base, data) = sender.encode(state);
+        let command = Commands::MovedPlayers(Snapshot { tick, time, seq, base, data });
+        new_bytes += bincode::serialize(&command).unwrap().len();
+
+        //Client only acknowledges what arrives
+        if rng.gen_bool(LOSS) {
+            continue;
+        }
+        let Commands::MovedPlayers(snapshot) = command else {
+            unreachable!();
+        };
+        if let Some(changed) = receiver.receive(snapshot.seq, snapshot.base, &snapshot.data) {
+            for p in changed {
+                client_view.insert(p.id, snapshot::quantise(p.pos));
+            }
+            sender.ack(snapshot.seq);
+        }
+    }
+
+    let old_tick = old_bytes as f32 / TICKS as f32;
+    let new_tick = new_bytes as f32 / TICKS as f32;
+    println!("{} players, {} ticks, {:.0}% loss", PLAYERS, TICKS, LOSS * 100.0);
+    println!("Full positions:  {:.0} bytes per client per tick, {:.0} for all clients", old_tick, old_tick * PLAYERS as f32);
+    println!("Delta snapshots: {:.0} bytes per client per tick, {:.0} for all clients", new_tick, new_tick * PLAYERS as f32);
+    println!("Saved {:.0}%", (1.0 - new_tick / old_tick) * 100.0);
+
+    //Once one more snapshot gets through, the client should have the latest positions despite the losses
+    let state: SnapshotState = (1..PLAYERS).map(|i| (i as u8, snapshot::quantise(positions[i]))).collect();
+    let (seq, base, data) = sender.encode(state);
+    if let Some(changed) = receiver.receive(seq, base, &data) {
+        for p in changed {
+            client_view.insert(p.id, snapshot::quantise(p.pos));
+        }
+    }
+    let converged = (1..PLAYERS).all(|i| client_view.get(&(i as u8)) == Some(&snapshot::quantise(positions[i])));
+    println!("Client converged: {}", converged);
+}
@@ -0,0 +1,19 @@
+//Throw arbitrary bytes at everything that decodes network input, none of it should panic or allocate without bound
+//Run with `cargo fuzz run commands` from the repository root
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use macroprox::{net_common, snapshot, wire};
+
+fuzz_target!(|data: &[u8]| {
+    //Anything accepted has to survive being sent again
+    if let Ok(command) = wire::decode_command(data) {
+        let again = bincode::serialize(&command).unwrap();
+        wire::decode_command(&again).expect("re-encoded command did not decode");
+    }
+    if let Ok(datagram) = wire::decode_datagram(data) {
+        wire::check_command(&datagram.command).expect("decoded datagram failed its checks");
+    }
+    let _ = net_common::peek_protocol_version(data);
+    let _ = snapshot::decode(None, data);
+});
@@ -0,0 +1,70 @@
+//Turning what a player types to find a server into an address to connect to
+//Takes IPv4 and IPv6 addresses and host names, each with or without a port, e.g "localhost", "10.0.0.2:5600",
+//"::1", "[::1]:5600" or "game.example.com:5600"
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+
+//Every address on this machine, IPv4 included on systems with dual stack sockets, which is nearly all of them
+pub fn any_address(port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port)
+}
+
+//Find the server at host, using default_port if it doesn't give one
+//Looking up a name blocks until the system resolver answers, which also checks /etc/hosts
+pub fn resolve(host: &str, default_port: u16) -> Result<SocketAddr, String> {
+    let host = host.trim();
+    if host.is_empty() {
+        return Err(String::from("No host given"));
+    }
+
+    //Literal addresses need no lookup
+    if let Ok(address) = host.parse::<SocketAddr>() {
+        return Ok(address);
+    }
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, default_port));
+    }
+
+    let (name, port) = split_port(host, default_port)?;
+    let addresses: Vec<SocketAddr> = (name, port)
+        .to_socket_addrs()
+        .map_err(|err| format!("Could not find {}: {}", name, err))?
+        .collect();
+    //IPv4 when there's a choice, servers bound to a single IPv4 address can't be reached over IPv6
+    addresses
+        .iter()
+        .find(|a| a.is_ipv4())
+        .or(addresses.first())
+        .copied()
+        .ok_or_else(|| format!("{} has no addresses", name))
+}
+
+//Split a port off the end of a name or bracketed IPv6 address
+fn split_port(host: &str, default_port: u16) -> Result<(&str, u16), String> {
+    if let Some(rest) = host.strip_prefix('[') {
+        let (inside, after) = rest
+            .split_once(']')
+            .ok_or_else(|| String::from("Missing ] after IPv6 address"))?;
+        if after.is_empty() {
+            return Ok((inside, default_port));
+        }
+        let port = after
+            .strip_prefix(':')
+            .ok_or_else(|| format!("Expected :port after ], got {}", after))?;
+        return Ok((inside, parse_port(port)?));
+    }
+
+    match host.rsplit_once(':') {
+        Some((name, port)) if !name.contains(':') => Ok((name, parse_port(port)?)),
+        //Several colons and not a valid address, likely an IPv6 address with a port but no brackets
+        Some(_) => Err(format!("{} is not a valid address, put IPv6 addresses in brackets to give a port, e.g [::1]:5508", host)),
+        None => Ok((host, default_port)),
+    }
+}
+
+fn parse_port(port: &str) -> Result<u16, String> {
+    match port.parse::<u16>() {
+        Ok(0) | Err(_) => Err(format!("{} is not a valid port", port)),
+        Ok(port) => Ok(port),
+    }
+}
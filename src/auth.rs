@@ -0,0 +1,70 @@
+//Server passwords, checked by challenge and response so the password itself never goes over the network
+//The server sends each new connection a random challenge, and the client proves it knows the password by
+//returning an HMAC of the challenge keyed with it. A recorded proof is no use against a different challenge.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub type Proof = [u8; 32];
+
+//Keeps proofs from being valid HMACs of anything else
+const CONTEXT: &[u8] = b"macroprox password";
+
+fn mac(password: &str, challenge: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(password.as_bytes()).unwrap();
+    mac.update(CONTEXT);
+    mac.update(&challenge.to_le_bytes());
+    mac
+}
+
+//Response to a challenge for the given password
+pub fn prove(password: &str, challenge: u64) -> Proof {
+    mac(password, challenge).finalize().into_bytes().into()
+}
+
+//Whether proof shows the client knows password, compared in constant time so timing gives nothing away
+pub fn check(password: &str, challenge: u64, proof: &Proof) -> bool {
+    mac(password, challenge).verify_slice(proof).is_ok()
+}
+
+//What a session token is held by, so a player taking over as host can recognise returning clients without ever
+//being given their tokens, which would let it pass as any of them
+pub type SessionDigest = [u8; 32];
+
+const SESSION_CONTEXT: &[u8] = b"macroprox migration";
+
+pub fn session_digest(session: u64) -> SessionDigest {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&session.to_le_bytes()).unwrap();
+    mac.update(SESSION_CONTEXT);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn right_password_is_accepted() {
+        assert!(check("secret", 42, &prove("secret", 42)));
+        assert!(check("", 42, &prove("", 42)));
+    }
+
+    #[test]
+    fn wrong_password_is_rejected() {
+        assert!(!check("secret", 42, &prove("guess", 42)));
+        assert!(!check("secret", 42, &prove("", 42)));
+    }
+
+    #[test]
+    fn replayed_proof_is_rejected() {
+        let proof = prove("secret", 42);
+        assert!(!check("secret", 43, &proof));
+    }
+
+    #[test]
+    fn session_digests_differ() {
+        assert_eq!(session_digest(42), session_digest(42));
+        assert_ne!(session_digest(42), session_digest(43));
+        assert!(!session_digest(42).windows(8).any(|w| w == 42u64.to_le_bytes()));
+    }
+}
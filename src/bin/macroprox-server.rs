@@ -0,0 +1,236 @@
+//Dedicated server, hosts a world without a window or a local player
+//Usage: macroprox-server [options], or macroprox-server query <address> to ask a running server for its status
+
+use macroprox::address;
+use macroprox::game::GameState;
+use macroprox::heartbeat;
+use macroprox::interest;
+use macroprox::maps::load_map_1;
+use macroprox::net_common::NetPosition;
+use macroprox::netsim;
+use macroprox::rate_limit::{Limit, RateLimits};
+use macroprox::secure;
+use macroprox::server::{self, ServerSettings};
+use macroprox::status;
+use macroprox::tick;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn print_usage() {
+    println!("Usage: macroprox-server [options]");
+    println!("       macroprox-server query <host>[:port] Print a running server's status and exit");
+    println!("  --bind <ip>          Address to listen on (default ::, which takes IPv4 too)");
+    println!("  --port <port>        Port to listen on (default 5508)");
+    println!("  --name <name>        Name shown to players looking for a game (default {})", ServerSettings::default().name);
+    println!("  --no-discovery       Don't answer LAN discovery queries");
+    println!("  --password <pw>      Players need this to join, MACROPROX_PASSWORD is used if not given");
+    println!("  --key <file>         Server's encryption key, made if it doesn't exist (default {})", secure::DEFAULT_KEY_FILE);
+    println!("  --record <file>      Record every message to and from clients, to watch again from the game's Replay menu");
+    println!("  --max-players <n>    Most players at once, 1-255 (default {})", server::DEFAULT_MAX_PLAYERS);
+    println!("  --max-spectators <n>\n                       Most people watching without playing at once, 0 for none (default {})", server::DEFAULT_MAX_SPECTATORS);
+    println!("  --timeout <secs>     Drop clients silent for this long (default {})", heartbeat::DEFAULT_TIMEOUT_SECS);
+    println!("  --grace <secs>       Keep a dropped player's slot this long for them to reconnect, 0 to remove straight away (default {})", server::DEFAULT_RECONNECT_GRACE_SECS);
+    println!("  --interest-radius <dist>\n                       Send clients players within this distance every update (default {})", interest::DEFAULT_INTEREST_RADIUS);
+    println!("  --tick-rate <hz>     Simulation and update rate, e.g 30, 60 or 128 (default {})", tick::DEFAULT_TICK_RATE);
+    let limits = RateLimits::default();
+    println!("  --rate-limit <n>     Most messages a second from one client (default {})", limits.total.rate);
+    println!("  --rate-limit-updates <n>\n                       Most inputs and snapshot acks a second from one client, of each (default {})", limits.updates.rate);
+    println!("  --rate-limit-other <n>\n                       Most of any other type of message a second from one client (default {})", limits.other.rate);
+    println!("  --rate-limit-queries <n>\n                       Most status and discovery queries answered a second for one address (default {})", limits.queries.rate);
+    println!("  --kick-after <n>     Kick a client once this many of its messages have been over the limit (default {})", limits.kick_after);
+    println!("  --ban-after <n>      Ban an address after this many kicks, 0 to never ban (default {})", limits.ban_after);
+    println!("  --ban-time <secs>    How long bans last (default {})", limits.ban_time.as_secs());
+    println!("Simulated network conditions, for testing:");
+    println!("{}", netsim::USAGE);
+}
+
+//Parse the value given for a flag, exit if it's invalid
+fn parse_value<T: FromStr>(flag: &str, value: &str) -> T {
+    match value.parse() {
+        Ok(v) => v,
+        Err(_) => {
+            println!("Invalid value {} for {}", value, flag);
+            process::exit(1);
+        }
+    }
+}
+
+//Parse a messages a second limit, bursts stay in proportion to the default's
+fn parse_limit(flag: &str, value: &str, default: Limit) -> Limit {
+    let rate: f32 = parse_value(flag, value);
+    if !rate.is_finite() || rate < 1.0 {
+        println!("{} must be at least 1 a second", flag);
+        process::exit(1);
+    }
+    Limit { rate, burst: rate * default.burst / default.rate }
+}
+
+//Read settings from command line arguments, exit on anything invalid
+fn parse_args() -> ServerSettings {
+    let mut settings = ServerSettings {
+        host_player: false,
+        //From the environment so it needn't show up in the process list
+        password: std::env::var("MACROPROX_PASSWORD").ok().filter(|p| !p.is_empty()),
+        ..Default::default()
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            print_usage();
+            process::exit(0);
+        }
+        if arg == "--no-discovery" {
+            settings.discoverable = false;
+            continue;
+        }
+
+        //Every other flag takes a value
+        let Some(value) = args.next() else {
+            println!("Missing value for {}", arg);
+            process::exit(1);
+        };
+
+        match settings.netsim.lock().unwrap().conditions.parse_flag(&arg, &value) {
+            Ok(true) => continue,
+            Ok(false) => (),
+            Err(err) => {
+                println!("{}", err);
+                process::exit(1);
+            }
+        }
+
+        match arg.as_str() {
+            "--bind" => settings.address.set_ip(parse_value::<IpAddr>(&arg, &value)),
+            "--port" => settings.address.set_port(parse_value(&arg, &value)),
+            "--name" => settings.name = value,
+            "--password" => settings.password = Some(value),
+            "--key" => settings.key_file = PathBuf::from(value),
+            "--record" => settings.record = Some(PathBuf::from(value)),
+            "--max-players" => {
+                settings.max_players = parse_value(&arg, &value);
+                if settings.max_players == 0 {
+                    println!("Need at least one player");
+                    process::exit(1);
+                }
+            }
+            "--max-spectators" => settings.max_spectators = parse_value(&arg, &value),
+            "--timeout" => {
+                let secs: u64 = parse_value(&arg, &value);
+                if secs == 0 {
+                    println!("Timeout must be at least a second");
+                    process::exit(1);
+                }
+                settings.timeout = Duration::from_secs(secs);
+            }
+            "--interest-radius" => {
+                settings.interest_radius = parse_value(&arg, &value);
+                if !settings.interest_radius.is_finite() || settings.interest_radius <= 0.0 {
+                    println!("Interest radius must be positive");
+                    process::exit(1);
+                }
+            }
+            "--tick-rate" => {
+                settings.tick_rate = parse_value(&arg, &value);
+                if settings.tick_rate == 0 || settings.tick_rate > tick::MAX_TICK_RATE {
+                    println!("Tick rate must be between 1 and {}", tick::MAX_TICK_RATE);
+                    process::exit(1);
+                }
+            }
+            "--rate-limit" => settings.rate_limits.total = parse_limit(&arg, &value, settings.rate_limits.total),
+            "--rate-limit-updates" => settings.rate_limits.updates = parse_limit(&arg, &value, settings.rate_limits.updates),
+            "--rate-limit-other" => settings.rate_limits.other = parse_limit(&arg, &value, settings.rate_limits.other),
+            "--rate-limit-queries" => settings.rate_limits.queries = parse_limit(&arg, &value, settings.rate_limits.queries),
+            "--kick-after" => {
+                settings.rate_limits.kick_after = parse_value(&arg, &value);
+                if settings.rate_limits.kick_after == 0 {
+                    println!("Need at least one message before kicking");
+                    process::exit(1);
+                }
+            }
+            "--ban-after" => settings.rate_limits.ban_after = parse_value(&arg, &value),
+            "--ban-time" => settings.rate_limits.ban_time = Duration::from_secs(parse_value(&arg, &value)),
+            "--grace" => settings.reconnect_grace = Duration::from_secs(parse_value(&arg, &value)),
+            _ => {
+                println!("Unknown argument {}", arg);
+                print_usage();
+                process::exit(1);
+            }
+        }
+    }
+
+    settings
+}
+
+//Print the status of the server at the given address, exit non-zero if it doesn't answer
+fn run_query(address: Option<String>) -> ! {
+    let Some(address) = address else {
+        println!("Missing address for query");
+        process::exit(1);
+    };
+    //Port is optional, defaulting to the usual game port
+    let address = match address::resolve(&address, ServerSettings::default().address.port()) {
+        Ok(a) => a,
+        Err(err) => {
+            println!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    match status::query(address, Duration::from_secs(1)) {
+        Ok(status) => {
+            println!("name: {}", status.name);
+            println!("protocol: {} (build {})", status.protocol_version, status.build_id);
+            println!("map: {}", status.map);
+            println!("uptime: {}s", status.uptime_secs);
+            println!("password: {}", if status.password { "yes" } else { "no" });
+            println!("players: {}/{}", status.players.len(), status.max_players);
+            for player in status.players {
+                println!("  {} {}", player.id, player.name);
+            }
+            println!("spectators: {}", status.spectators.len());
+            for name in status.spectators {
+                println!("  {}", name);
+            }
+            process::exit(0);
+        }
+        Err(err) => {
+            println!("No status from {}: {}", address, err);
+            process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("query") {
+        run_query(args.next());
+    }
+
+    let settings = parse_args();
+
+    //Same spawn the game uses for its default window size
+    let game_state = GameState {
+        spawn: NetPosition { x: 400.0, y: 300.0 },
+        ..Default::default()
+    };
+    let state_lock = Arc::new(Mutex::new(game_state));
+    load_map_1(&state_lock);
+
+    //Ask the host to stop on Ctrl-C, it checks the flag on its next update
+    let shutdown = Arc::clone(&settings.shutdown);
+    let res = ctrlc::set_handler(move || {
+        shutdown.store(true, Ordering::Relaxed);
+    });
+    if let Err(err) = res {
+        println!("Could not set Ctrl-C handler: {}", err);
+        process::exit(1);
+    }
+
+    server::run_host(state_lock, settings);
+}
@@ -0,0 +1,59 @@
+//Challenges for queries from people who haven't joined, i.e status and LAN discovery
+//Anyone can send a query with someone else's address on it, and a reply bigger than the query would turn the server
+//into an amplifier for flooding that address. So a query first gets back a challenge no bigger than itself, and only
+//a query repeating the challenge for its address gets the real reply, like Source's A2S challenges.
+//Challenges are a keyed hash of the address and the time, so nothing is kept for each one handed out.
+
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::clock;
+
+//A challenge is good for between one and two of these
+const LIFETIME: Duration = Duration::from_secs(30);
+
+pub struct Challenges {
+    secret: [u8; 32],
+    start: Instant,
+}
+
+impl Default for Challenges {
+    fn default() -> Challenges {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        Challenges { secret, start: clock::now() }
+    }
+}
+
+impl Challenges {
+    fn window(&self) -> u64 {
+        clock::since(self.start).as_secs() / LIFETIME.as_secs()
+    }
+
+    //Never 0, which queries use to ask for a challenge
+    fn make(&self, ip: IpAddr, window: u64) -> u64 {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        mac.update(&window.to_le_bytes());
+        let hash = mac.finalize().into_bytes();
+        u64::from_le_bytes(hash[..8].try_into().unwrap()) | 1
+    }
+
+    pub fn issue(&self, ip: IpAddr) -> u64 {
+        self.make(ip, self.window())
+    }
+
+    //Whether a query from ip has a challenge handed out to it recently
+    pub fn check(&self, ip: IpAddr, challenge: u64) -> bool {
+        let window = self.window();
+        challenge == self.make(ip, window) || (window > 0 && challenge == self.make(ip, window - 1))
+    }
+}
@@ -1,163 +1,676 @@
 use super::game;
 use super::net_common;
+use crate::address;
+use crate::auth;
+use crate::clock;
 use crate::game::GameReadiness;
+use crate::heartbeat::{self, Heartbeat};
+use crate::interpolation::Interpolation;
+use crate::movement::Prediction;
+use crate::memory_network::{MemoryNetwork, MemoryNode};
+use crate::netsim::NetSim;
 use crate::menu::GameSettings;
 use crate::net_common::Commands;
-use crate::net_common::NetPosition;
+use crate::net_common::DatagramSeq;
 use crate::net_common::RegistrationInfo;
+use crate::net_common::Successor;
+use crate::secure::{self, Channel, Handshake, Key, StaticKey, Trust};
+use crate::server::{self, ServerSettings};
+use crate::snapshot::SnapshotReceiver;
+use crate::transport::Network;
+use crate::wire;
 use message_io::network::Endpoint;
-use message_io::network::{NetEvent, Transport};
+use message_io::adapters::udp::UdpConnectConfig;
+use message_io::network::{NetEvent, Transport, TransportConnect};
 use message_io::node::NodeEvent;
 use message_io::node::{self};
-use std::f32::NAN;
-use std::net::IpAddr;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 enum Signal {
     TrySendUpdate,
+    TryBindUdp,
+    Heartbeat,
+    ConnectionLost,
+    Reconnect,
+    NetSim, //Simulated network has messages due
+    Deliver(Endpoint, Vec<u8>), //Message held back by the simulated network, now due
 }
 
-pub fn run_client(settings: GameSettings, state_lock: Arc<Mutex<game::GameState>>) {
-    let address = std::net::SocketAddr::new(IpAddr::V4(settings.host.unwrap()), settings.port);
+//How many times to ask the server to bind UDP before sticking with TCP
+const MAX_BIND_ATTEMPTS: u32 = 20;
+//Unacknowledged inputs resent in each datagram, in case earlier ones were lost
+const MAX_REDUNDANT_INPUTS: usize = 32;
+
+//Wait between reconnect attempts, doubled after each failure up to the max
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(250);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(8);
+//Server only keeps the player this long by default, no point trying after
+const RECONNECT_GIVE_UP: Duration = Duration::from_secs(30);
+//Failed reconnects before giving up on a player host and moving to its successor
+const MIGRATE_AFTER_ATTEMPTS: u32 = 3;
+
+//Exponential backoff, so a server that's down isn't flooded
+fn reconnect_delay(attempts: u32) -> Duration {
+    RECONNECT_MIN_DELAY.saturating_mul(1 << attempts.min(6)).min(RECONNECT_MAX_DELAY)
+}
+
+//Send over the encrypted TCP connection, nothing goes before the handshake is done
+fn send_reliable<N: Network<Signal>>(sim: &mut NetSim<Signal, N>, server: Endpoint, channel: &mut Option<Channel>, command: &Commands) {
+    if let Some(channel) = channel {
+        let tosend = channel.seal(&bincode::serialize(command).unwrap());
+        sim.send(server, &tosend);
+    }
+}
+
+//Datagrams start with the UDP token, so the server knows whose keys decrypt them
+fn send_datagram<N: Network<Signal>>(
+    sim: &mut NetSim<Signal, N>,
+    udp: Endpoint,
+    token: u64,
+    seq: &mut DatagramSeq,
+    channel: &mut Option<Channel>,
+    command: &Commands,
+) {
+    if let Some(channel) = channel {
+        let mut tosend = token.to_le_bytes().to_vec();
+        tosend.extend(channel.seal_datagram(&seq.wrap(command)));
+        sim.send(udp, &tosend);
+    }
+}
+
+//message-io binds UDP sockets to an IPv4 address unless told otherwise, which can't reach IPv6 servers
+fn connect_udp<N: Network<Signal>>(net: &N, address: SocketAddr) -> std::io::Result<(Endpoint, SocketAddr)> {
+    let local: IpAddr = match address {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let config = UdpConnectConfig::default().with_source_address(SocketAddr::new(local, 0));
+    net.connect(TransportConnect::Udp(config), address)
+}
 
+//Check the server has the same key as last time, remembering it if this is the first connection
+//A successor's key is vouched for by the host that picked it, which is trusted whatever was there before
+fn trust_server(
+    path: &Path,
+    address: SocketAddr,
+    server_key: &Key,
+    vouched: Option<&Key>,
+    state_lock: &Arc<Mutex<game::GameState>>,
+) -> bool {
+    let fingerprint = secure::fingerprint(server_key);
+    let address = address.to_string();
+    if vouched == Some(server_key) {
+        if let Err(err) = secure::remember_server(path, &address, &fingerprint) {
+            println!("Could not save server key: {}", err);
+        }
+        return true;
+    }
+    match secure::check_server(path, &address, &fingerprint) {
+        Trust::Known => true,
+        Trust::New => {
+            println!("First connection to {}, trusting its key {}", address, fingerprint);
+            if let Err(err) = secure::remember_server(path, &address, &fingerprint) {
+                println!("Could not save server key: {}", err);
+            }
+            true
+        }
+        Trust::Changed(old) => {
+            println!("Key for {} changed from {} to {}, not connecting", address, old, fingerprint);
+            net_common::set_error(
+                state_lock,
+                format!(
+                    "Server key has changed, someone may be pretending to be the server (was {}, now {}). If it was reset on purpose, remove {} from {}",
+                    old,
+                    fingerprint,
+                    address,
+                    path.display()
+                ),
+            );
+            false
+        }
+    }
+}
+
+pub fn run_client(settings: GameSettings, state_lock: Arc<Mutex<game::GameState>>) {
     let (handler, listener) = node::split::<Signal>();
-    let con_res = handler.network().connect(Transport::FramedTcp, address);
+    let takeover = Rc::new(RefCell::new(None));
+    let host_state = Arc::clone(&state_lock);
+    let Some(client) = start_client(handler, settings, state_lock, Rc::clone(&takeover)) else {
+        return;
+    };
+    listener.for_each(client);
+
+    //Picked to take over from a host that left, so carry on hosting from this thread
+    if let Some(settings) = takeover.take() {
+        server::run_host(host_state, settings);
+    }
+}
 
-    let server: Endpoint;
+//Run a client on an in-memory network, it handles events as the network is stepped
+pub fn run_client_in(network: &MemoryNetwork, settings: GameSettings, state_lock: Arc<Mutex<game::GameState>>) {
+    network.spawn(|net: MemoryNode<Signal>| {
+        let takeover = Rc::new(RefCell::new(None));
+        let host_state = Arc::clone(&state_lock);
+        let mut client = start_client(net.clone(), settings, state_lock, Rc::clone(&takeover))?;
+        Some(move |event: NodeEvent<Signal>| {
+            client(event);
+            //Hosting carries on from the same network
+            if let Some(settings) = takeover.take() {
+                if let Some(network) = net.network() {
+                    server::run_host_in(&network, Arc::clone(&host_state), settings);
+                }
+            }
+        })
+    });
+}
+
+//Connect to the server, returning what handles events from then on, or None if it can't start
+//Sets takeover if this client is picked to host after the host leaves, which is up to the caller to start
+fn start_client<N: Network<Signal> + 'static>(
+    net: N,
+    settings: GameSettings,
+    state_lock: Arc<Mutex<game::GameState>>,
+    takeover: Rc<RefCell<Option<ServerSettings>>>,
+) -> Option<impl FnMut(NodeEvent<Signal>)> {
+    //Looked up on the network thread, the menu would freeze until the resolver answered
+    //Port setting is only used if the host doesn't give one
+    let found = match settings.host {
+        Some(host) => Ok(host),
+        None => address::resolve(&settings.host_name, settings.port),
+    };
+    let mut address = match found {
+        Ok(found) => found,
+        Err(err) => {
+            net_common::set_error(&state_lock, err);
+            return None;
+        }
+    };
+
+    let con_res = net.connect(Transport::FramedTcp.into(), address);
+
+    let mut server: Endpoint;
 
     match con_res {
         Ok((end, _)) => server = end,
         Err(err) => {
             net_common::set_error(&state_lock, err.to_string());
-            return;
+            return None;
         }
     }
 
-    let mut pos = NetPosition { x: NAN, y: NAN };
+    let mut state = state_lock.lock().unwrap();
+    state.interpolation.delay_ms = settings.interp_delay_ms;
+    state.spectating = settings.spectate;
+    drop(state);
 
-    listener.for_each(move |event| match event {
-        NodeEvent::Network(net_event) => match net_event {
-            NetEvent::Connected(_endpoint, _ok) => {
-                println!("Endpoint: {}", _endpoint);
-                if !_ok {
-                    net_common::set_error(&state_lock, String::from("Could not connect"));
-                    return;
-                }
+    //Position traffic goes over UDP once the server has tied it to this session
+    let mut udp: Option<Endpoint> = None;
+    let mut udp_token: u64 = 0;
+    let mut udp_bound = false;
+    let mut udp_seq = DatagramSeq::default();
+    let mut bind_attempts = 0;
+
+    let timeout = Duration::from_secs(settings.timeout_secs);
+    let mut heartbeat = Heartbeat::new();
+    net.signal_after(Signal::Heartbeat, heartbeat::PING_INTERVAL);
 
-                println!("Connected! Requesting registration....");
-                let tosend = bincode::serialize(&Commands::RegisterPlayer(RegistrationInfo{colour: settings.player_colour, name: settings.player_name.clone()})).unwrap();
-                let _status = handler.network().send(_endpoint, &tosend);   
+    //Token for taking our player back after losing the connection
+    let mut session: Option<u64> = None;
+    let mut connected = false;
+    let mut reconnect_since: Option<Instant> = None;
+    let mut reconnect_attempts: u32 = 0;
+    let mut sending_updates = false;
+
+    let mut snapshots = SnapshotReceiver::default();
+    //Straight through unless network conditions are being simulated
+    let mut sim = NetSim::new(net.clone(), || Signal::NetSim, Arc::clone(&settings.netsim));
+
+    //Encryption for the current connection, set up by the handshake
+    let mut handshake: Option<Handshake> = None;
+    let mut channel: Option<Channel> = None;
+
+    //Who to move to if the player hosting leaves, and the key this client would host with if it's picked
+    let host_key = StaticKey::generate();
+    let mut successor: Option<Successor> = None;
+    let mut host_leaving = false;
+    let mut vouched: Option<Key> = None;
+    Some(move |event: NodeEvent<Signal>| {
+        //Messages the simulated network held back come back as signals, handled as if they'd just arrived
+        let delivered;
+        let event = match event {
+            NodeEvent::Network(NetEvent::Message(endpoint, data)) if sim.receive(endpoint, data) => return,
+            NodeEvent::Signal(Signal::Deliver(endpoint, data)) => {
+                sim.handled();
+                delivered = data;
+                NodeEvent::Network(NetEvent::Message(endpoint, &delivered))
             }
-            NetEvent::Accepted(_, _) => unreachable!(), // Only generated by listening
-            NetEvent::Message(_endpoint, data) => {
-                let res = bincode::deserialize(&data);
-
-                match res {
-                    Ok(dat) => {
-                        let mut state = state_lock.lock().unwrap();
-                        match dat {
-                            Commands::Move(_) => (), //Not for client
-                            Commands::RegisterPlayer(_) => (), //Not for client
-                            Commands::SendMap(map) => {
-                                for b in map.buildings {
-                                    state.buildings.push(b);
-                                }
-                            }
-                            Commands::SendPlayerInfo(player_info) => {
-                                for i in player_info.players {
-                                    state.players.insert(i.id, i);
+            event => event,
+        };
+        match event {
+            NodeEvent::Network(net_event) => match net_event {
+                NetEvent::Connected(_endpoint, _ok) if Some(_endpoint) == udp => {
+                    if !_ok {
+                        println!("Could not open UDP, using TCP only");
+                        udp = None;
+                        return;
+                    }
+                    net.signal(Signal::TryBindUdp);
+                }
+                NetEvent::Connected(_endpoint, _ok) => {
+                    println!("Endpoint: {}", _endpoint);
+                    if !_ok {
+                        if reconnect_since.is_some() {
+                            //Server may still be coming back, try again later
+                            let delay = reconnect_delay(reconnect_attempts);
+                            net.signal_after(Signal::Reconnect, delay);
+                            return;
+                        }
+                        net_common::set_error(&state_lock, String::from("Could not connect"));
+                        return;
+                    }
+
+                    //Silence from here on means the server is gone
+                    heartbeat.heard();
+                    connected = true;
+
+                    //Everything is encrypted, registration goes once that's set up and the server has sent its challenge
+                    println!("Connected! Starting handshake....");
+                    let (started, request) = Handshake::start();
+                    handshake = Some(started);
+                    sim.send(_endpoint, &request);
+                }
+                NetEvent::Accepted(_, _) => unreachable!(), // Only generated by listening
+                NetEvent::Message(_endpoint, data) => {
+                    let res: Result<Commands, String> = if Some(_endpoint) == udp {
+                        let Some(data) = channel.as_ref().and_then(|c| c.open_datagram(data)) else {
+                            println!("Could not decrypt datagram from server");
+                            return;
+                        };
+                        match wire::decode_datagram(&data) {
+                            Ok(datagram) => {
+                                //Drop anything older than what's already arrived
+                                if !udp_seq.accept(datagram.seq) {
+                                    return;
                                 }
+                                Ok(datagram.command)
                             }
-                            Commands::AllowClientReady(id) => {
-                                //Server has sent everything, ready to start
-                                state.own_player = id;
-                                state.ready = GameReadiness::Ready;
+                            Err(err) => Err(err),
+                        }
+                    } else if let Some(started) = handshake.take() {
+                        //First reply finishes the handshake
+                        let Some((established, server_key)) = started.finish(data) else {
+                            println!("Handshake with server failed");
+                            //Servers turn some clients away before the handshake, e.g banned ones
+                            let error = match wire::decode_command(data) {
+                                Ok(Commands::RejectRegistration(reason)) => String::from("Server rejected connection: ") + &reason,
+                                _ => String::from("Could not set up an encrypted connection, the server may be running a different version"),
+                            };
+                            net_common::set_error(&state_lock, error);
+                            net.stop();
+                            return;
+                        };
+                        if !trust_server(&settings.known_servers, address, &server_key, vouched.as_ref(), &state_lock) {
+                            net.stop();
+                            return;
+                        }
+                        vouched = None;
+                        channel = Some(established);
+                        return;
+                    } else {
+                        let Some(data) = channel.as_mut().and_then(|c| c.open(data)) else {
+                            //Can't trust anything after a message that's been tampered with
+                            println!("Could not decrypt message from server");
+                            net.remove(server.resource_id());
+                            net.signal(Signal::ConnectionLost);
+                            return;
+                        };
+                        wire::decode_command(&data)
+                    };
 
-                                //Start network update loop
-                                handler.signals().send_with_timer(Signal::TrySendUpdate, Duration::from_millis(100));
-                            }
-                            Commands::MovedPlayers(dat) => {
-
-                                // println!("Got data?");
-                                match state.ready { //Only try and update if ready
-                                    GameReadiness::Error(_) => (),
-                                    GameReadiness::Loading => (),
-                                    GameReadiness::Ready => {
-                                        //Set updated positions
-                                        for val in dat {                                         
-                                            //Don't update self from server info
-                                            if val.id != state.own_player {
-                                                match state.players.get_mut(&val.id) {
-                                                    None => {
-                                                        println!("Warning! Server tried to update non-existent player {}", val.id);
-                                                    },
-                                                    Some(p) => {
-                                                        p.position = val.pos;
-                                                    }
-                                                }
-                                            }
-                                            
+                    match res {
+                        Ok(dat) => {
+                            heartbeat.heard();
+                            let mut state = state_lock.lock().unwrap();
+                            match dat {
+                                Commands::Inputs(_) => (), //Not for client
+                                Commands::AckInput(ack) => {
+                                    //Server position is authoritative, replay what it hasn't seen on top of it
+                                    let own_player = state.own_player;
+                                    let game::GameState { players, buildings, prediction, .. } = &mut *state;
+                                    if let Some(p) = players.get_mut(&own_player) {
+                                        if let Some(pos) = prediction.reconcile(buildings, &ack) {
+                                            p.position = pos;
                                         }
                                     }
                                 }
-                            },
-                            Commands::AddPlayer(player) => {
-                                state.players.insert(player.id, player);
-                            },
-                            Commands::RemovePlayer(id) => {
-                                let t = state.players.get(&id);
-                                match t {
-                                    None => {
-                                        println!("Warning! Server tried to remove non-existent player");
-                                    },
-                                    Some(d) => {
-                                        println!("{} left the game", d.name);
-                                        state.players.remove(&id);
+                                Commands::RegisterPlayer(_) => (), //Not for client
+                                Commands::Challenge(challenge) => {
+                                    println!("Requesting registration....");
+                                    //Password never goes over the network, only proof of it
+                                    let password = (!settings.password.is_empty()).then(|| auth::prove(&settings.password, challenge));
+                                    let register = Commands::RegisterPlayer(RegistrationInfo{
+                                        protocol_version: net_common::PROTOCOL_VERSION,
+                                        build_id: String::from(net_common::BUILD_ID),
+                                        colour: settings.player_colour,
+                                        name: settings.player_name.clone(),
+                                        session,
+                                        password,
+                                        host_key: Some(host_key.public()),
+                                        spectator: settings.spectate,
+                                    });
+                                    send_reliable(&mut sim, server, &mut channel, &register);
+                                }
+                                Commands::ClientReady => (), //Not for client
+                                Commands::AckSnapshot(_) => (), //Not for client
+                                Commands::BindUdp(token) => {
+                                    udp_token = token;
+                                    match connect_udp(&net, address) {
+                                        Ok((endpoint, _)) => udp = Some(endpoint),
+                                        Err(err) => println!("Could not open UDP: {}, using TCP only", err),
+                                    }
+                                }
+                                Commands::Ping(time) => {
+                                    send_reliable(&mut sim, server, &mut channel, &Commands::Pong(time));
+                                }
+                                Commands::Pong(time) => {
+                                    heartbeat.pong(time);
+                                    state.link = heartbeat.stats();
+                                }
+                                Commands::Session(token) => {
+                                    session = Some(token);
+                                }
+                                Commands::Reconnecting(id) => {
+                                    state.reconnecting.insert(id);
+                                }
+                                Commands::Reconnected(id) => {
+                                    state.reconnecting.remove(&id);
+                                }
+                                Commands::EnterInterest(p) => state.enter_interest(p),
+                                Commands::LeaveInterest(id) => state.leave_interest(id),
+                                Commands::UdpBound => {
+                                    if !udp_bound {
+                                        println!("Position updates now over UDP");
+                                    }
+                                    udp_bound = true;
+                                }
+                                Commands::RejectRegistration(reason) => {
+                                    println!("Server rejected registration: {}", reason);
+                                    state.ready = GameReadiness::Error(String::from("Server rejected connection: ") + &reason);
+                                }
+                                Commands::Successor(next) => {
+                                    successor = next;
+                                }
+                                Commands::HostLeaving => {
+                                    println!("Host is leaving");
+                                    host_leaving = true;
+                                }
+                                Commands::Warning(reason) => {
+                                    println!("Warning from server: {}", reason);
+                                }
+                                Commands::Kick(reason) => {
+                                    //Set before the disconnect so it isn't taken as a dropped connection to retry
+                                    println!("Kicked by server: {}", reason);
+                                    state.ready = GameReadiness::Error(String::from("Kicked by server: ") + &reason);
+                                }
+                                Commands::SendMap(map) => {
+                                    //Replaces anything from before a reconnect
+                                    state.buildings = map.buildings;
+                                }
+                                Commands::SendPlayerInfo(player_info) => state.set_player_info(player_info),
+                                Commands::AllowClientReady(id) => {
+                                    //Server has sent everything, ready to start
+                                    state.own_player = id;
+                                    state.ready = GameReadiness::Ready;
+
+                                    //Let the server know it can start sending updates
+                                    send_reliable(&mut sim, server, &mut channel, &Commands::ClientReady);
+
+                                    //Start network update loop, it's still running if this is a reconnect
+                                    if reconnect_since.take().is_some() {
+                                        println!("Reconnected as player {}", id);
+                                    }
+                                    if !sending_updates {
+                                        sending_updates = true;
+                                        net.signal_after(Signal::TrySendUpdate, Duration::from_millis(100));
                                     }
                                 }
-                            },
+                                Commands::MovedPlayers(dat) => {
+                                    //Unpack against an earlier snapshot, if that's unknown the server falls back to an older one
+                                    let Some(positions) = snapshots.receive(dat.seq, dat.base, &dat.data) else {
+                                        println!("Could not decode snapshot {} against {}", dat.seq, dat.base);
+                                        return;
+                                    };
+
+                                    //Let the server encode against this one from now on
+                                    let ack = Commands::AckSnapshot(dat.seq);
+                                    match udp {
+                                        Some(udp) if udp_bound => {
+                                            send_datagram(&mut sim, udp, udp_token, &mut udp_seq, &mut channel, &ack);
+                                        }
+                                        _ => send_reliable(&mut sim, server, &mut channel, &ack),
+                                    }
+
+                                    state.apply_snapshot(dat.time, positions);
+                                },
+                                Commands::AddPlayer(player) => state.add_player(player),
+                                Commands::RemovePlayer(id) => match state.remove_player(id) {
+                                    None => println!("Warning! Server tried to remove non-existent player"),
+                                    Some(d) => println!("{} left the game", d.name),
+                                },
+                            }
+
+                            drop(state);
                         }
+                        Err(err) => {
+                            println!("{}", err);
+                            //Nothing from the server makes sense yet, so it's probably running another version
+                            let mut state = state_lock.lock().unwrap();
+                            if let GameReadiness::Loading = state.ready {
+                                state.ready = GameReadiness::Error(format!(
+                                    "Could not read server message, it may be running a different version (ours is protocol {}, build {})",
+                                    net_common::PROTOCOL_VERSION,
+                                    net_common::BUILD_ID
+                                ));
+                            }
+                            drop(state);
+                        }
+                    }
 
-                        drop(state);
+                    // println!("Received from server: {}", String::from_utf8_lossy(data));
+                }
+                NetEvent::Disconnected(_endpoint) if Some(_endpoint) == udp => {
+                    println!("Lost UDP, using TCP only");
+                    udp = None;
+                    udp_bound = false;
+                }
+                NetEvent::Disconnected(_endpoint) if _endpoint == server => {
+                    println!("Disconnected");
+                    net.signal(Signal::ConnectionLost);
+                }
+                NetEvent::Disconnected(_) => (), //Connection already replaced
+            },
+            NodeEvent::Signal(signal) => match signal {
+                Signal::TrySendUpdate => {
+                    if !connected {
+                        net.signal_after(Signal::TrySendUpdate, Duration::from_millis(10));
+                        return;
                     }
-                    Err(err) => {
-                        println!("{}", err);
+
+                    let mut state = state_lock.lock().unwrap();
+                    match udp {
+                        //Datagrams can be lost, so keep resending until the server acknowledges
+                        Some(udp) if udp_bound => {
+                            let inputs = state.prediction.unacked(MAX_REDUNDANT_INPUTS);
+                            if !inputs.is_empty() {
+                                send_datagram(&mut sim, udp, udp_token, &mut udp_seq, &mut channel, &Commands::Inputs(inputs));
+                            }
+                        }
+                        _ => {
+                            let inputs = state.prediction.take_unsent();
+
+                            //Only send if there's been new movement
+                            if !inputs.is_empty() {
+                                send_reliable(&mut sim, server, &mut channel, &Commands::Inputs(inputs));
+                            }
+                        }
                     }
+                    drop(state);
+
+                    //Re-schedule data send
+                    net.signal_after(Signal::TrySendUpdate, Duration::from_millis(10));
+
                 }
+                Signal::TryBindUdp => {
+                    let Some(udp) = udp else {
+                        return;
+                    };
+                    if udp_bound {
+                        return;
+                    }
+                    if bind_attempts >= MAX_BIND_ATTEMPTS {
+                        println!("Server never answered over UDP, using TCP only");
+                        return;
+                    }
 
-                // println!("Received from server: {}", String::from_utf8_lossy(data));
-            }
-            NetEvent::Disconnected(_endpoint) => {
-                let mut game = state_lock.lock().unwrap();
-                game.ready = GameReadiness::Error(String::from("Got Disconnected"));
-                println!("Disconnected");
-                drop(game);
-            }
-        },
-        NodeEvent::Signal(signal) => match signal {
-            Signal::TrySendUpdate => {
-                let state = state_lock.lock().unwrap();
-                let p = state.players.get(&state.own_player).unwrap();
-                let p_pos = p.position;
-                drop(state);
-
-                //Check if client position has changed
-                //Right now the only part of client state
-                if !p_pos.equals(&pos) {
-                    pos = p_pos;
-                    let tosend = bincode::serialize(&Commands::Move(p_pos))
-                    .unwrap();
-
-                    let _status = handler.network().send(server, &tosend);
+                    //Keep asking until the server answers, the request itself could be lost
+                    bind_attempts += 1;
+                    send_datagram(&mut sim, udp, udp_token, &mut udp_seq, &mut channel, &Commands::BindUdp(udp_token));
+                    net.signal_after(Signal::TryBindUdp, Duration::from_millis(250));
+                }
+                Signal::NetSim => {
+                    for (endpoint, data) in sim.poll() {
+                        net.signal(Signal::Deliver(endpoint, data));
+                    }
                 }
+                Signal::Deliver(..) => (), //Turned back into a message above
+                Signal::Heartbeat => {
+                    net.signal_after(Signal::Heartbeat, heartbeat::PING_INTERVAL);
+                    if !connected {
+                        return;
+                    }
 
-                //Re-schedule data send
-                handler
-                    .signals()
-                    .send_with_timer(Signal::TrySendUpdate, Duration::from_millis(10));
+                    //A half-open connection never disconnects by itself, so give up on it here
+                    if heartbeat.silent_for() > timeout {
+                        println!("Server timed out");
+                        net.remove(server.resource_id());
+                        net.signal(Signal::ConnectionLost);
+                        return;
+                    }
 
-            }
-        },
-    });
+                    send_reliable(&mut sim, server, &mut channel, &Commands::Ping(heartbeat.ping_time()));
+                }
+                Signal::ConnectionLost => {
+                    connected = false;
+                    //Next connection does its own handshake
+                    handshake = None;
+                    channel = None;
+                    if let Some(endpoint) = udp.take() {
+                        net.remove(endpoint.resource_id());
+                    }
+                    udp_bound = false;
+                    udp_seq = DatagramSeq::default();
+                    bind_attempts = 0;
+                    //New connection starts a new run of snapshots
+                    snapshots = SnapshotReceiver::default();
+
+                    let mut state = state_lock.lock().unwrap();
+                    //Keep a more specific error if there is one, e.g a rejection
+                    if matches!(state.ready, GameReadiness::Error(_)) {
+                        return;
+                    }
+                    //Can only get the same player back once registered, spectators just start watching again
+                    if session.is_none() && !settings.spectate {
+                        state.ready = GameReadiness::Error(String::from("Got Disconnected"));
+                        return;
+                    }
+
+                    //Host has gone for good, carry on with the player it picked to take over
+                    if host_leaving {
+                        host_leaving = false;
+                        if let Some(next) = successor.take() {
+                            state.remove_player(next.host);
+
+                            if next.id == state.own_player {
+                                println!("Host left, taking over the game");
+                                //Everyone is shown from here on, straight from the simulation
+                                state.hosting = true;
+                                state.hidden.clear();
+                                state.interpolation = Interpolation::new(settings.interp_delay_ms);
+                                state.prediction = Prediction::default();
+                                state.link = None;
+                                state.ready = GameReadiness::Ready;
+                                drop(state);
+                                *takeover.borrow_mut() = Some(ServerSettings {
+                                    address: address::any_address(next.address.port()),
+                                    name: format!("{}'s game", settings.player_name),
+                                    password: next.password,
+                                    key: Some(host_key.clone()),
+                                    migrated: next.sessions,
+                                    max_players: next.max_players,
+                                    rate_limits: next.rate_limits,
+                                    timeout,
+                                    shutdown: Arc::clone(&settings.shutdown),
+                                    netsim: Arc::clone(&settings.netsim),
+                                    ..Default::default()
+                                });
+                                net.stop();
+                                return;
+                            }
+
+                            //Loopback is the old host's own machine, which is where this client was connected
+                            let ip = if next.address.ip().is_loopback() { address.ip() } else { next.address.ip() };
+                            address = SocketAddr::new(ip, next.address.port());
+                            vouched = Some(next.key);
+                            //Snapshot times are on the new host's clock, which started from nothing
+                            state.interpolation = Interpolation::new(settings.interp_delay_ms);
+                            println!("Host left, moving to player {} at {}", next.id, address);
+                        }
+                    }
+
+                    //Server starts counting inputs again for the new connection
+                    state.ready = GameReadiness::Reconnecting;
+                    state.prediction = Prediction::default();
+                    drop(state);
+
+                    println!("Lost connection, reconnecting");
+                    reconnect_since = Some(clock::now());
+                    reconnect_attempts = 0;
+                    net.signal(Signal::Reconnect);
+                }
+                Signal::Reconnect => {
+                    let Some(since) = reconnect_since else {
+                        return;
+                    };
+                    if clock::since(since) > RECONNECT_GIVE_UP {
+                        println!("Could not reconnect");
+                        net_common::set_error(&state_lock, String::from("Lost connection to server"));
+                        net.stop();
+                        return;
+                    }
+
+                    reconnect_attempts += 1;
+                    //Player host may have quit without saying, move on if it doesn't come back quickly
+                    if !host_leaving && successor.is_some() && reconnect_attempts > MIGRATE_AFTER_ATTEMPTS {
+                        println!("Host isn't coming back");
+                        host_leaving = true;
+                        net.signal(Signal::ConnectionLost);
+                        return;
+                    }
+                    match net.connect(Transport::FramedTcp.into(), address) {
+                        Ok((endpoint, _)) => server = endpoint,
+                        Err(err) => {
+                            println!("Reconnect failed: {}", err);
+                            let delay = reconnect_delay(reconnect_attempts);
+                            net.signal_after(Signal::Reconnect, delay);
+                        }
+                    }
+                }
+            },
+        }
+    })
 }
@@ -0,0 +1,24 @@
+//Time as the simulation sees it, the system clock unless something on this thread is driving time itself
+//The in-memory network does that, so tests get through seconds of play in no time and run the same every time.
+//Anything timing the game (ticks, heartbeats, rate limits, interpolation) should ask here instead of Instant::now.
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    static DRIVEN: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+pub fn now() -> Instant {
+    DRIVEN.with(Cell::get).unwrap_or_else(Instant::now)
+}
+
+//Same as earlier.elapsed(), on this clock
+pub fn since(earlier: Instant) -> Duration {
+    now().saturating_duration_since(earlier)
+}
+
+//Drive time on this thread from here on, or give it back to the system clock with None
+pub fn drive(now: Option<Instant>) {
+    DRIVEN.with(|driven| driven.set(now));
+}
@@ -0,0 +1,146 @@
+//Finding servers on the local network
+//Clients broadcast a query to a well known port, and every server listening there replies with what it's running
+//Servers answer the broadcast with a challenge, which the client sends straight back to each, see challenge.rs
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::wire;
+
+pub const DISCOVERY_PORT: u16 = 5509;
+//Marks discovery packets, so anything else sent to the port is ignored
+const MAGIC: [u8; 4] = *b"MPXD";
+//Servers not heard from for this long are taken off the list
+const EXPIRY: Duration = Duration::from_secs(5);
+//Replies are a name and a few numbers, anything bigger isn't from a server
+const MAX_BYTES: u64 = 1024;
+
+//What a server tells clients looking for games
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ServerInfo {
+    pub protocol_version: u16,
+    pub name: String,
+    pub map: String,
+    pub players: u8,
+    pub max_players: u8,
+    pub port: u16, //Game port, can differ from the discovery port
+    pub password: bool, //Whether joining needs a password
+}
+
+#[derive(Serialize, Deserialize)]
+enum Discovery {
+    Query(u64), //Challenge the server handed out, 0 to ask for one
+    Challenge(u64),
+    Reply(ServerInfo),
+}
+
+fn encode(message: &Discovery) -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    data.extend(bincode::serialize(message).unwrap());
+    data
+}
+
+fn decode(data: &[u8]) -> Option<Discovery> {
+    let body = data.strip_prefix(&MAGIC)?;
+    wire::decode(body, MAX_BYTES).ok()
+}
+
+//Challenge a query came with, if it is one
+pub fn query_challenge(data: &[u8]) -> Option<u64> {
+    match decode(data) {
+        Some(Discovery::Query(challenge)) => Some(challenge),
+        _ => None,
+    }
+}
+
+pub fn challenge(challenge: u64) -> Vec<u8> {
+    encode(&Discovery::Challenge(challenge))
+}
+
+pub fn reply(info: ServerInfo) -> Vec<u8> {
+    encode(&Discovery::Reply(info))
+}
+
+//Server found on the network
+#[derive(Clone)]
+pub struct LanServer {
+    pub address: SocketAddr, //Address to connect to for the game
+    pub info: ServerInfo,
+}
+
+//Keeps a list of servers answering on the local network, updated in the background
+pub struct LanBrowser {
+    socket: UdpSocket,
+    servers: Arc<Mutex<HashMap<SocketAddr, (LanServer, Instant)>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl LanBrowser {
+    pub fn new() -> std::io::Result<LanBrowser> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+        //Wake up now and then to check whether to stop
+        socket.set_read_timeout(Some(Duration::from_millis(200)))?;
+
+        let browser = LanBrowser {
+            socket: socket.try_clone()?,
+            servers: Arc::new(Mutex::new(HashMap::new())),
+            stop: Arc::new(AtomicBool::new(false)),
+        };
+
+        let servers = Arc::clone(&browser.servers);
+        let stop = Arc::clone(&browser.stop);
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            while !stop.load(Ordering::Relaxed) {
+                let Ok((len, from)) = socket.recv_from(&mut buf) else {
+                    continue;
+                };
+                match decode(&buf[..len]) {
+                    Some(Discovery::Reply(info)) => {
+                        let address = SocketAddr::new(from.ip(), info.port);
+                        let mut servers = servers.lock().unwrap();
+                        servers.insert(address, (LanServer { address, info }, Instant::now()));
+                    }
+                    Some(Discovery::Challenge(challenge)) => {
+                        let _ = socket.send_to(&encode(&Discovery::Query(challenge)), from);
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        Ok(browser)
+    }
+
+    //Ask every server on the network to reply
+    pub fn refresh(&self) {
+        let query = encode(&Discovery::Query(0));
+        if let Err(err) = self.socket.send_to(&query, (Ipv4Addr::BROADCAST, DISCOVERY_PORT)) {
+            //No network to broadcast on, there could still be servers on this machine
+            println!("Could not broadcast discovery query: {}", err);
+            let _ = self.socket.send_to(&query, (Ipv4Addr::LOCALHOST, DISCOVERY_PORT));
+        }
+    }
+
+    //Servers that have replied recently, sorted by name
+    pub fn servers(&self) -> Vec<LanServer> {
+        let mut servers = self.servers.lock().unwrap();
+        servers.retain(|_, (_, seen)| seen.elapsed() < EXPIRY);
+        let mut list: Vec<LanServer> = servers.values().map(|(s, _)| s.clone()).collect();
+        list.sort_by(|a, b| a.info.name.cmp(&b.info.name).then(a.address.cmp(&b.address)));
+        list
+    }
+}
+
+impl Drop for LanBrowser {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
@@ -1,16 +1,19 @@
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-};
+use std::collections::{HashMap, HashSet};
 
+#[cfg(feature = "client")]
 use macroquad::{
     audio::{self, set_sound_volume},
     prelude::*,
-    text,
 };
 
-use crate::net_common::{NetBuilding, NetPlayer, NetPosition};
+use crate::heartbeat::LinkStats;
+use crate::interpolation::Interpolation;
+#[cfg(feature = "client")]
+use crate::movement::{PLAYER_HEIGHT, PLAYER_WIDTH};
+use crate::movement::Prediction;
+use crate::net_common::{NetBuilding, NetPlayer, NetPlayerInfo, NetPosition, PositionMap};
 
+#[cfg(feature = "client")]
 pub struct Player {
     pub id: u8,
     pub name: String,
@@ -18,6 +21,7 @@ pub struct Player {
     pub colour: Color,
 }
 
+#[cfg(feature = "client")]
 impl Player {
     fn draw(&self, offset: Vec2) {
         let head_height = 7.0;
@@ -45,29 +49,16 @@ impl Player {
         draw_rectangle(pos.x, pos.y, self.get_width(), head_height, BEIGE);
     }
 
-    //Get min and max extents
-    fn get_min_max(&self, position: Vec2) -> (Vec2, Vec2) {
-        let min = Vec2 {
-            x: position.x,
-            y: position.y + self.get_height(),
-        };
-        let max = Vec2 {
-            x: position.x + self.get_width(),
-            y: position.y,
-        };
-
-        (min, max)
-    }
-
     const fn get_height(&self) -> f32 {
-        20.0
+        PLAYER_HEIGHT
     }
 
     const fn get_width(&self) -> f32 {
-        10.0
+        PLAYER_WIDTH
     }
 }
 
+#[cfg(feature = "client")]
 pub struct Building {
     pub position: Vec2,
     pub width: f32,
@@ -75,27 +66,15 @@ pub struct Building {
     pub colour: Color,
 }
 
+#[cfg(feature = "client")]
 impl Building {
     fn draw(&self, offset: Vec2) {
         let pos = self.position + offset;
         draw_rectangle(pos.x, pos.y, self.width, self.height, self.colour);
     }
-
-    //Get min and max extents
-    fn get_min_max(&self) -> (Vec2, Vec2) {
-        let min = Vec2 {
-            x: self.position.x,
-            y: self.position.y + self.height,
-        };
-        let max = Vec2 {
-            x: self.position.x + self.width,
-            y: self.position.y,
-        };
-
-        (min, max)
-    }
 }
 
+#[cfg(feature = "client")]
 //Mostly for testing out audio system before implementing chat etc
 pub struct Audio {
     pub position: Vec2,
@@ -103,6 +82,7 @@ pub struct Audio {
     pub sound: audio::Sound,
 }
 
+#[cfg(feature = "client")]
 impl Audio {
     fn draw(&self, offset: Vec2) {
         let pos = self.position + offset;
@@ -119,6 +99,7 @@ impl Audio {
 pub enum GameReadiness {
     Ready,
     Loading,
+    Reconnecting,
     Error(String),
 }
 
@@ -130,7 +111,23 @@ pub struct GameState {
     pub own_player: u8,
     pub ready: GameReadiness,
     pub players: HashMap<u8, NetPlayer>,
+    //Players whose connection dropped, kept in case they come back
+    pub reconnecting: HashSet<u8>,
+    //Players outside this client's area of interest, not drawn
+    pub hidden: HashSet<u8>,
     pub buildings: Vec<NetBuilding>,
+    //Shown to players looking for a game
+    pub map_name: String,
+    //Snapshots of remote players, only filled in on clients
+    pub interpolation: Interpolation,
+    //Own inputs not yet confirmed by the server, only used on clients
+    pub prediction: Prediction,
+    //Latency to the server, only known on clients
+    pub link: Option<LinkStats>,
+    //This process runs the server, so own movement is authoritative and needn't be predicted
+    pub hosting: bool,
+    //Watching without a player, the camera moves freely
+    pub spectating: bool,
 }
 
 impl Default for GameState {
@@ -141,11 +138,87 @@ impl Default for GameState {
             own_player: 0,
             // ready: GameReadiness::Loading,
             players: HashMap::new(),
+            reconnecting: HashSet::new(),
+            hidden: HashSet::new(),
             buildings: Vec::new(),
+            map_name: String::new(),
+            interpolation: Interpolation::default(),
+            prediction: Prediction::default(),
+            link: None,
+            hosting: false,
+            spectating: false,
+        }
+    }
+}
+
+//What the server tells a client about the world, shared by the client and replays of what a client was sent
+impl GameState {
+    //Replace the player list, e.g after a reconnect
+    pub fn set_player_info(&mut self, player_info: NetPlayerInfo) {
+        self.players.clear();
+        self.hidden.clear();
+        for i in player_info.players {
+            //Others are shown once the server says they're in range
+            if i.id != player_info.your_num {
+                self.hidden.insert(i.id);
+            }
+            self.players.insert(i.id, i);
+        }
+        self.reconnecting = player_info.reconnecting.into_iter().collect();
+    }
+
+    pub fn add_player(&mut self, player: NetPlayer) {
+        self.hidden.insert(player.id);
+        self.players.insert(player.id, player);
+    }
+
+    //Returns the player that was removed, if it existed
+    pub fn remove_player(&mut self, id: u8) -> Option<NetPlayer> {
+        let player = self.players.remove(&id)?;
+        self.reconnecting.remove(&id);
+        self.hidden.remove(&id);
+        self.interpolation.remove_player(id);
+        Some(player)
+    }
+
+    pub fn enter_interest(&mut self, p: PositionMap) {
+        //Start interpolating afresh, the old samples could be from anywhere
+        self.hidden.remove(&p.id);
+        self.interpolation.remove_player(p.id);
+        if let Some(player) = self.players.get_mut(&p.id) {
+            player.position = p.pos;
+        }
+    }
+
+    pub fn leave_interest(&mut self, id: u8) {
+        self.hidden.insert(id);
+        self.interpolation.remove_player(id);
+    }
+
+    //Positions from a decoded snapshot, only used once ready
+    pub fn apply_snapshot(&mut self, time: u64, positions: Vec<PositionMap>) {
+        if !matches!(self.ready, GameReadiness::Ready) {
+            return;
+        }
+        //Buffer for drawing, minus own player which is never interpolated
+        let own_player = self.own_player;
+        let remote: Vec<_> = positions.iter().filter(|p| p.id != own_player).cloned().collect();
+        self.interpolation.add_snapshot(time, &remote);
+
+        //Set updated positions, but don't update self from server info
+        for val in positions {
+            if val.id == own_player {
+                continue;
+            }
+            match self.players.get_mut(&val.id) {
+                None => println!("Warning! Server tried to update non-existent player {}", val.id),
+                Some(p) => p.position = val.pos,
+            }
         }
     }
 }
 
+#[cfg(feature = "client")]
 pub struct GameObject {
     pub ready: GameReadiness,
     pub spawn: Vec2,
@@ -153,8 +226,11 @@ pub struct GameObject {
     pub players: HashMap<u8, Player>,
     pub buildings: Vec<Building>,
     pub audio_sources: Vec<Audio>,
+    pub link: Option<LinkStats>,
+    pub spectating: bool,
 }
 
+#[cfg(feature = "client")]
 impl GameObject {
     //Move the player by moving the world, pass in current player pos
     pub fn draw(&self, player_pos: Vec2) {
@@ -179,77 +255,33 @@ impl GameObject {
             }
         }
 
-        //Draw own player on top
-        let op = self.players.get(&self.own_player).unwrap();
-        op.draw(diff);
+        //Draw own player on top, spectators have none
+        if let Some(op) = self.players.get(&self.own_player) {
+            op.draw(diff);
+        }
     }
 
     //Set audio effects on all sources
     pub fn resolve_audio(&mut self) {
         let base_intensity = 0.7;
+        //Spectators have no player to hear from
+        let Some(op) = self.players.get(&self.own_player) else {
+            return;
+        };
         for a in &self.audio_sources {
             //Get dist from player
-            let mut op = self.players.get_mut(&self.own_player).unwrap();
             let dist = op.position.distance(a.position) / 300.0;
 
             set_sound_volume(&a.sound, base_intensity / (dist * dist).max(1.0));
         }
     }
 
-    //Return position of player after resolving collision
-    //Will eventually return closer position if collision detected instead of 'cancelling'
-    pub fn resolve_collide(&self, player: &Player, player_pos: Vec2) -> Vec2 {
-        //Calculate min and max extents of player
-        let (min_player, max_player) = player.get_min_max(player_pos);
-        let mut ret_value = player_pos;
-
-        for i in &self.buildings {
-            let (min, max) = i.get_min_max();
-            let d1x = min.x - max_player.x;
-            let d1y = max_player.y - min.y;
-            let d2x = min_player.x - max.x;
-            let d2y = max.y - min_player.y;
-
-            //Check if definitely not colliding
-            if d1x > 0.0 || d1y > 0.0 {
-                continue;
-            }
-
-            if d2x > 0.0 || d2y > 0.0 {
-                continue;
-            }
-
-            //Check origin of movement
-
-            let (omin_player, omax_player) = player.get_min_max(player.position);
-
-            let od1x = min.x - omax_player.x;
-            let od1y = omax_player.y - min.y;
-            let od2x = omin_player.x - max.x;
-            let od2y = max.y - omin_player.y;
-
-            //If player was previously in x bounds safely, then it's the x change responsible for collision
-            //So disable x component, keep y (checked afterwards)
-            if od1x > 0.0 || od2x > 0.0 {
-                ret_value.x = player.position.x;
-            }
-
-            if od1y > 0.0 || od2y > 0.0 {
-                ret_value.y = player.position.y;
-            }
-
-            //Don't return value yet, disabling one movement component but keeping another could interfere with other object collisions
-        }
-
-        //Return requested position, possibly with some axis movement reset (for sliding along walls)
-        ret_value
-    }
-
     pub fn add_player(&mut self, player: Player) {
         self.players.insert(player.id, player);
     }
 }
 
+#[cfg(feature = "client")]
 impl Default for GameObject {
     fn default() -> GameObject {
         GameObject {
@@ -259,6 +291,8 @@ impl Default for GameObject {
             players: HashMap::new(),
             buildings: Vec::new(),
             audio_sources: Vec::new(),
+            link: None,
+            spectating: false,
         }
     }
 }
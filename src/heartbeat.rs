@@ -0,0 +1,83 @@
+//Keeps connections alive and measures latency
+//Each side pings with its own clock and the other echoes the time back, so clocks never need to agree
+
+use std::time::{Duration, Instant};
+
+use crate::clock;
+
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+//Silence for this long means the other side is gone, even if the socket still looks open
+pub const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+//Smoothing as in TCP's RTT estimate (RFC 6298)
+const RTT_GAIN: f32 = 0.125;
+const JITTER_GAIN: f32 = 0.25;
+
+//Latency of a connection, in milliseconds
+#[derive(Copy, Clone)]
+pub struct LinkStats {
+    pub rtt_ms: f32,
+    pub jitter_ms: f32,
+}
+
+pub struct Heartbeat {
+    start: Instant,
+    last_heard: Instant,
+    stats: Option<LinkStats>,
+}
+
+impl Heartbeat {
+    pub fn new() -> Heartbeat {
+        let now = clock::now();
+        Heartbeat {
+            start: now,
+            last_heard: now,
+            stats: None,
+        }
+    }
+
+    //Time to put in a ping, echoed back in the pong
+    pub fn ping_time(&self) -> u64 {
+        clock::since(self.start).as_millis() as u64
+    }
+
+    //Anything arriving counts as a sign of life, not just pongs
+    pub fn heard(&mut self) {
+        self.last_heard = clock::now();
+    }
+
+    pub fn silent_for(&self) -> Duration {
+        clock::since(self.last_heard)
+    }
+
+    //Update the estimate from the echoed time of one of our pings
+    pub fn pong(&mut self, sent: u64) {
+        let now = self.ping_time();
+        //Echo from the future can only be a bad or spoofed pong
+        if sent > now {
+            return;
+        }
+        let sample = (now - sent) as f32;
+
+        self.stats = Some(match self.stats {
+            None => LinkStats {
+                rtt_ms: sample,
+                jitter_ms: sample / 2.0,
+            },
+            Some(s) => LinkStats {
+                jitter_ms: s.jitter_ms + JITTER_GAIN * ((s.rtt_ms - sample).abs() - s.jitter_ms),
+                rtt_ms: s.rtt_ms + RTT_GAIN * (sample - s.rtt_ms),
+            },
+        });
+    }
+
+    pub fn stats(&self) -> Option<LinkStats> {
+        self.stats
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Heartbeat {
+        Heartbeat::new()
+    }
+}
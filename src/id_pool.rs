@@ -0,0 +1,61 @@
+//Hands out player ids, reusing ones freed by players that left
+
+use std::collections::BTreeSet;
+
+pub struct IdPool {
+    free: BTreeSet<u8>,
+    limit: u8,
+}
+
+impl IdPool {
+    //Pool of ids 0 to limit - 1
+    pub fn new(limit: u8) -> IdPool {
+        IdPool {
+            free: (0..limit).collect(),
+            limit,
+        }
+    }
+
+    //Keep an id from being given out, for the host's own player and players carried over from another host
+    pub fn take(&mut self, id: u8) {
+        self.free.remove(&id);
+    }
+
+    //Lowest free id, or none if the server is full
+    pub fn allocate(&mut self) -> Option<u8> {
+        self.free.pop_first()
+    }
+
+    pub fn release(&mut self, id: u8) {
+        if id < self.limit {
+            self.free.insert(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lowest_free_id_is_reused_first() {
+        let mut pool = IdPool::new(10);
+        pool.take(0);
+        assert_eq!((pool.allocate(), pool.allocate(), pool.allocate()), (Some(1), Some(2), Some(3)));
+        pool.release(3);
+        pool.release(1);
+        assert_eq!((pool.allocate(), pool.allocate(), pool.allocate()), (Some(1), Some(3), Some(4)));
+    }
+
+    #[test]
+    fn full_pool_gives_nothing_until_released() {
+        let mut pool = IdPool::new(2);
+        assert_eq!((pool.allocate(), pool.allocate()), (Some(0), Some(1)));
+        assert_eq!(pool.allocate(), None);
+        //Ids past the limit, e.g spectators', never go in the pool
+        pool.release(200);
+        assert_eq!(pool.allocate(), None);
+        pool.release(1);
+        assert_eq!(pool.allocate(), Some(1));
+    }
+}
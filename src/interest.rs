@@ -0,0 +1,137 @@
+//Area of interest for each client, so it's only sent players it could see or hear
+//Nearby players are sent every update, further ones occasionally, and anyone beyond that not at all
+
+use std::collections::{HashMap, HashSet};
+
+use crate::net_common::{NetPlayer, NetPosition};
+
+//Covers the screen from the centre with some margin, and the whole range audio can be heard at
+pub const DEFAULT_INTEREST_RADIUS: f32 = 800.0;
+//Players up to this many radii away are still sent, just less often
+const FAR_MULTIPLIER: f32 = 2.0;
+//Updates between sends for far players
+pub const FAR_UPDATE_INTERVAL: u32 = 10;
+//Extra distance before a player leaves the set, so someone on the edge doesn't flicker in and out
+const HYSTERESIS: f32 = 100.0;
+
+fn distance(a: NetPosition, b: NetPosition) -> f32 {
+    ((a.x - b.x) * (a.x - b.x) + (a.y - b.y) * (a.y - b.y)).sqrt()
+}
+
+//Players one client is currently told about
+pub struct Interest {
+    radius: f32,
+    known: HashSet<u8>,
+}
+
+impl Interest {
+    pub fn new(radius: f32) -> Interest {
+        Interest {
+            radius,
+            known: HashSet::new(),
+        }
+    }
+
+    //Work out who is in range of a player at centre, returns who entered and who left
+    pub fn update(&mut self, own: u8, centre: NetPosition, players: &HashMap<u8, NetPlayer>) -> (Vec<u8>, Vec<u8>) {
+        let far = self.radius * FAR_MULTIPLIER;
+        let mut entered = Vec::new();
+        let mut left = Vec::new();
+
+        for (id, player) in players {
+            if *id == own {
+                continue;
+            }
+            let d = distance(centre, player.position);
+            if self.known.contains(id) {
+                if d > far + HYSTERESIS {
+                    self.known.remove(id);
+                    left.push(*id);
+                }
+            } else if d <= far {
+                self.known.insert(*id);
+                entered.push(*id);
+            }
+        }
+
+        //Players that have gone from the game are announced with RemovePlayer instead
+        self.known.retain(|id| players.contains_key(id));
+        (entered, left)
+    }
+
+    //E.g for the player taking over as host, who needs the whole game ready
+    pub fn set_radius(&mut self, radius: f32) {
+        self.radius = radius;
+    }
+
+    pub fn known(&self) -> impl Iterator<Item = &u8> {
+        self.known.iter()
+    }
+
+    //Whether a known player's position should be brought up to date this update
+    pub fn wants_update(&self, centre: NetPosition, pos: NetPosition, update: u32) -> bool {
+        distance(centre, pos) <= self.radius || update % FAR_UPDATE_INTERVAL == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net_common::NetColour;
+
+    const RADIUS: f32 = 100.0;
+    const CENTRE: NetPosition = NetPosition { x: 0.0, y: 0.0 };
+
+    //Player 1 at distance x from the centre
+    fn at(x: f32) -> HashMap<u8, NetPlayer> {
+        let player = NetPlayer {
+            position: NetPosition { x, y: 0.0 },
+            id: 1,
+            name: String::from("a"),
+            colour: NetColour { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+        };
+        HashMap::from([(1, player)])
+    }
+
+    #[test]
+    fn players_enter_in_range() {
+        let mut interest = Interest::new(RADIUS);
+        assert_eq!(interest.update(0, CENTRE, &at(RADIUS * FAR_MULTIPLIER + 1.0)), (vec![], vec![]));
+        assert_eq!(interest.update(0, CENTRE, &at(RADIUS * FAR_MULTIPLIER)), (vec![1], vec![]));
+        assert_eq!(interest.update(0, CENTRE, &at(0.0)), (vec![], vec![]));
+        assert!(interest.known().eq([1].iter()));
+    }
+
+    #[test]
+    fn players_leave_out_of_range() {
+        let mut interest = Interest::new(RADIUS);
+        interest.update(0, CENTRE, &at(0.0));
+        let gone = RADIUS * FAR_MULTIPLIER + HYSTERESIS + 1.0;
+        assert_eq!(interest.update(0, CENTRE, &at(gone)), (vec![], vec![1]));
+        assert_eq!(interest.known().count(), 0);
+    }
+
+    #[test]
+    fn players_on_the_edge_stay_put() {
+        //Between where players enter and where they leave, nothing changes either way
+        let edge = RADIUS * FAR_MULTIPLIER + HYSTERESIS / 2.0;
+        let mut inside = Interest::new(RADIUS);
+        inside.update(0, CENTRE, &at(0.0));
+        let mut outside = Interest::new(RADIUS);
+        for _ in 0..3 {
+            assert_eq!(inside.update(0, CENTRE, &at(edge)), (vec![], vec![]));
+            assert_eq!(outside.update(0, CENTRE, &at(edge)), (vec![], vec![]));
+        }
+        assert_eq!((inside.known().count(), outside.known().count()), (1, 0));
+    }
+
+    #[test]
+    fn spectators_see_everyone() {
+        let mut interest = Interest::new(f32::INFINITY);
+        let far = NetPosition { x: 1.0e7, y: -1.0e7 };
+        let mut players = at(0.0);
+        players.get_mut(&1).unwrap().position = far;
+        assert_eq!(interest.update(0, CENTRE, &players), (vec![1], vec![]));
+        assert!((1..FAR_UPDATE_INTERVAL).all(|update| interest.wants_update(CENTRE, far, update)));
+    }
+}
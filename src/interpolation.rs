@@ -0,0 +1,155 @@
+//Buffers position snapshots from the server so other players move smoothly
+//Remote players are drawn slightly in the past, between the snapshots either side of the render time
+//See https://developer.valvesoftware.com/wiki/Source_Multiplayer_Networking#Entity_interpolation
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+use crate::clock;
+use crate::net_common::{NetPosition, PositionMap};
+
+pub const DEFAULT_DELAY_MS: u64 = 100;
+
+//How much history to keep per player, in server milliseconds
+const HISTORY_MS: u64 = 1000;
+
+struct Sample {
+    time: u64,
+    pos: NetPosition,
+}
+
+//Snapshots for a single player, oldest first
+#[derive(Default)]
+pub struct SnapshotBuffer {
+    samples: VecDeque<Sample>,
+}
+
+impl SnapshotBuffer {
+    pub fn push(&mut self, time: u64, pos: NetPosition) {
+        //Drop anything older than what's already buffered, it arrived too late
+        if let Some(last) = self.samples.back() {
+            if time <= last.time {
+                return;
+            }
+        }
+        self.samples.push_back(Sample { time, pos });
+
+        //Forget old history, but always keep two samples to interpolate between
+        while self.samples.len() > 2 && self.samples[0].time + HISTORY_MS < time {
+            self.samples.pop_front();
+        }
+    }
+
+    //Record that the position was unchanged up to this time
+    //Without this, a player standing still then moving would slide across the whole gap
+    pub fn hold(&mut self, time: u64) {
+        let len = self.samples.len();
+        if len == 0 || self.samples[len - 1].time >= time {
+            return;
+        }
+
+        let last = self.samples[len - 1].pos;
+        if len >= 2 && self.samples[len - 2].pos.equals(&last) {
+            //Already holding, just stretch the hold
+            self.samples[len - 1].time = time;
+        } else {
+            self.push(time, last);
+        }
+    }
+
+    //Position at the given server time, clamped to the oldest and newest samples
+    pub fn sample(&self, time: u64) -> Option<NetPosition> {
+        let newest = self.samples.back()?;
+        if time >= newest.time {
+            return Some(newest.pos);
+        }
+
+        //Newest is later than time, so there's always a next sample
+        let next = self.samples.iter().position(|s| s.time > time).unwrap();
+        if next == 0 {
+            return Some(self.samples[0].pos);
+        }
+
+        let a = &self.samples[next - 1];
+        let b = &self.samples[next];
+        let t = (time - a.time) as f32 / (b.time - a.time) as f32;
+        Some(NetPosition {
+            x: a.pos.x + (b.pos.x - a.pos.x) * t,
+            y: a.pos.y + (b.pos.y - a.pos.y) * t,
+        })
+    }
+}
+
+//Snapshot buffers for every remote player, plus an estimate of the server clock
+pub struct Interpolation {
+    //How far in the past remote players are drawn
+    pub delay_ms: u64,
+    buffers: HashMap<u8, SnapshotBuffer>,
+    //Smallest (local - server) time seen, i.e from the quickest packet
+    clock_offset: Option<i64>,
+    start: Instant,
+    //Local time set from outside instead of read from the system clock, for replays that pause, seek and change speed
+    clock: Option<u64>,
+}
+
+impl Interpolation {
+    pub fn new(delay_ms: u64) -> Interpolation {
+        Interpolation {
+            delay_ms,
+            buffers: HashMap::new(),
+            clock_offset: None,
+            start: clock::now(),
+            clock: None,
+        }
+    }
+
+    fn local_ms(&self) -> i64 {
+        match self.clock {
+            Some(ms) => ms as i64,
+            None => clock::since(self.start).as_millis() as i64,
+        }
+    }
+
+    //Take local time from here from now on
+    pub fn set_clock(&mut self, ms: u64) {
+        self.clock = Some(ms);
+    }
+
+    //Add a server snapshot, players not in it are taken to have stayed still
+    pub fn add_snapshot(&mut self, time: u64, positions: &[PositionMap]) {
+        let offset = self.local_ms() - time as i64;
+        self.clock_offset = Some(self.clock_offset.map_or(offset, |o| o.min(offset)));
+
+        for p in positions {
+            self.buffers.entry(p.id).or_default().push(time, p.pos);
+        }
+
+        //Moved players already have a sample at this time, so this only affects the rest
+        for buffer in self.buffers.values_mut() {
+            buffer.hold(time);
+        }
+    }
+
+    pub fn remove_player(&mut self, id: u8) {
+        self.buffers.remove(&id);
+    }
+
+    //Server time to draw remote players at
+    pub fn render_time(&self) -> Option<u64> {
+        let offset = self.clock_offset?;
+        let server_now = self.local_ms() - offset;
+        Some((server_now - self.delay_ms as i64).max(0) as u64)
+    }
+
+    //Interpolated position of a player, if any snapshots have arrived for them
+    pub fn position(&self, id: u8) -> Option<NetPosition> {
+        let time = self.render_time()?;
+        self.buffers.get(&id)?.sample(time)
+    }
+}
+
+impl Default for Interpolation {
+    fn default() -> Interpolation {
+        Interpolation::new(DEFAULT_DELAY_MS)
+    }
+}
@@ -0,0 +1,32 @@
+//Shared game and networking code, used by both the game and the dedicated server
+
+pub mod address;
+pub mod auth;
+pub mod challenge;
+pub mod clock;
+pub mod discovery;
+pub mod game;
+pub mod heartbeat;
+pub mod id_pool;
+pub mod interest;
+pub mod interpolation;
+pub mod maps;
+pub mod memory_network;
+pub mod movement;
+pub mod net_common;
+pub mod netsim;
+pub mod rate_limit;
+pub mod replay;
+pub mod secure;
+pub mod server;
+pub mod snapshot;
+pub mod status;
+pub mod tick;
+pub mod transport;
+pub mod validation;
+pub mod wire;
+
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "client")]
+pub mod menu;
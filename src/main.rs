@@ -1,26 +1,105 @@
-use game::{GameObject, GameReadiness, GameState, Player};
-use macroquad::audio::Sound;
+use macroprox::game::{GameObject, GameReadiness, GameState, Player};
+use macroprox::maps::load_map_1;
+use macroprox::movement::{self, MoveInput};
+use macroprox::menu::{main_menu, GameSettings, GameType};
+use macroprox::net_common::{NetBuilding, NetPlayer, NetPosition};
+use macroprox::netsim::{self, SharedSim};
+use macroprox::replay::{Record, Replay};
+use macroprox::{address, client, server};
 use macroquad::telemetry::frame;
+use macroquad::prelude::*;
 use macroquad::ui::{hash, root_ui};
-use macroquad::{
-    audio::{self, set_sound_volume},
-    prelude::*,
-};
-use maps::load_map_1;
-use menu::{main_menu, GameSettings, GameType};
-use net_common::{NetBuilding, NetPlayer, NetPosition};
+use std::ops::Range;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 use std::thread;
-mod client;
-mod game;
-mod menu;
-mod net_common;
-mod server;
-mod maps;
-
-//Load map into object
+use std::time::Duration;
+
+//Replay controls
+const REPLAY_SKIP_MS: f64 = 5000.0;
+const MIN_REPLAY_SPEED: f64 = 0.125;
+const MAX_REPLAY_SPEED: f64 = 8.0;
+const REPLAY_BAR_HEIGHT: f32 = 10.0;
+
+//Network simulation overlay limits
+const MAX_SIM_DELAY_MS: f32 = 1000.0;
+const MAX_SIM_BANDWIDTH_KBPS: f32 = 2000.0;
+
+//Simulated network conditions can be given on the command line, e.g --sim-latency 100 --sim-loss 5
+fn parse_netsim_args(sim: &SharedSim) {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_default();
+        match sim.lock().unwrap().conditions.parse_flag(&arg, &value) {
+            Ok(true) => (),
+            Ok(false) => {
+                println!("Unknown argument {}, options are:\n{}", arg, netsim::USAGE);
+                std::process::exit(1);
+            }
+            Err(err) => {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+//Sliders for the simulated network conditions, and what they've done so far
+fn draw_netsim_overlay(sim: &SharedSim) {
+    let mut controls = sim.lock().unwrap();
+    let stats = controls.stats;
+    let c = &mut controls.conditions;
+    let mut latency = c.latency_ms as f32;
+    let mut jitter = c.jitter_ms as f32;
+    let mut loss = c.loss * 100.0;
+    let mut duplicate = c.duplicate * 100.0;
+    let mut reorder = c.reorder * 100.0;
+    let mut bandwidth = c.bandwidth_kbps as f32;
+
+    let size = Vec2::new(380.0, 250.0);
+    let position = Vec2::new(screen_width() - size.x - 10.0, 10.0);
+    root_ui().window(hash!(), position, size, |ui| {
+        ui.label(None, "Simulated network (F3 to hide)");
+        ui.slider(hash!(), "Latency ms", Range { start: 0.0, end: MAX_SIM_DELAY_MS }, &mut latency);
+        ui.slider(hash!(), "Jitter ms", Range { start: 0.0, end: MAX_SIM_DELAY_MS }, &mut jitter);
+        ui.slider(hash!(), "Loss %", Range { start: 0.0, end: 100.0 }, &mut loss);
+        ui.slider(hash!(), "Duplicate %", Range { start: 0.0, end: 100.0 }, &mut duplicate);
+        ui.slider(hash!(), "Reorder %", Range { start: 0.0, end: 100.0 }, &mut reorder);
+        ui.slider(hash!(), "Bandwidth kbps", Range { start: 0.0, end: MAX_SIM_BANDWIDTH_KBPS }, &mut bandwidth);
+        ui.label(None, "Bandwidth 0 is no limit");
+        ui.separator();
+        ui.label(None, &format!("Messages {}  queued {}", stats.messages, stats.queued));
+        ui.label(
+            None,
+            &format!("Lost {}  duplicated {}  reordered {}", stats.lost, stats.duplicated, stats.reordered),
+        );
+        if ui.button(None, "Reset") {
+            latency = 0.0;
+            jitter = 0.0;
+            loss = 0.0;
+            duplicate = 0.0;
+            reorder = 0.0;
+            bandwidth = 0.0;
+        }
+    });
+
+    c.latency_ms = latency.round() as u32;
+    c.jitter_ms = jitter.round() as u32;
+    c.loss = loss / 100.0;
+    c.duplicate = duplicate / 100.0;
+    c.reorder = reorder / 100.0;
+    c.bandwidth_kbps = bandwidth.round() as u32;
+}
+
+//Load map into object, and add own player at spawn
 async fn load_game_map(state_lock: &Arc<Mutex<GameState>>, player: &NetPlayer) {
-    load_map_1(state_lock, player);
+    load_map_1(state_lock);
+
+    let mut game = state_lock.lock().unwrap();
+    let mut p = player.clone();
+    p.position = game.spawn;
+    game.players.insert(p.id, p);
+    drop(game);
 }
 
 //Load a client game
@@ -38,7 +117,7 @@ async fn load_client(game: &Arc<Mutex<GameState>>) {
                 message = String::from("Encountered error: ") + er;
                 error = true;
             }
-            GameReadiness::Loading => (),
+            GameReadiness::Loading | GameReadiness::Reconnecting => (),
             GameReadiness::Ready => {
                 done = true;
             }
@@ -81,12 +160,29 @@ fn game_from_state(game: &mut GameObject, state_lock: &Arc<Mutex<GameState>>) {
     }
 
     for (i, p) in &state.players {
+        if state.hidden.contains(i) {
+            continue;
+        }
+
+        //Other players are drawn in the past between snapshots, once there are any
+        let mut position = p.position;
+        if *i != state.own_player {
+            if let Some(pos) = state.interpolation.position(*i) {
+                position = pos;
+            }
+        }
+
+        let mut name = p.name.clone();
+        if state.reconnecting.contains(i) {
+            name += " (reconnecting)";
+        }
+
         game.players.insert(
             *i,
             Player {
                 id: *i,
-                name: p.name.clone(),
-                position: p.position.to_vec2(),
+                name,
+                position: position.to_vec2(),
                 colour: p.colour.to_col(),
             },
         );
@@ -94,6 +190,8 @@ fn game_from_state(game: &mut GameObject, state_lock: &Arc<Mutex<GameState>>) {
 
     game.own_player = state.own_player;
     game.ready = state.ready.clone();
+    game.link = state.link;
+    game.spectating = state.spectating;
     drop(state);
 }
 
@@ -114,29 +212,183 @@ fn game_to_state(game: &GameObject, state_lock: &Arc<Mutex<GameState>>) {
     drop(state);
 }
 
-//Transfer game object state to state, that was changed by self (don't overwrite client data if server)
-fn own_changes_to_state(game: &GameObject, state_lock: &mut Arc<Mutex<GameState>>) {
-    let player = game.players.get(&game.own_player).unwrap();
+//Move own player in state, keeping the input for prediction if the server has to confirm it
+//Done under one lock so a correction from the server can't be overwritten by a stale frame
+fn move_own_player(state_lock: &Arc<Mutex<GameState>>, input: MoveInput) {
     let mut state = state_lock.lock().unwrap();
-    state
-        .players
-        .insert(game.own_player, NetPlayer::from_player(&player));
+    if !matches!(state.ready, GameReadiness::Ready) || !input.is_moving() {
+        return;
+    }
 
+    let own = state.own_player;
+    let Some(player) = state.players.get(&own) else {
+        return;
+    };
+    let pos = movement::apply_input(&state.buildings, player.position, &input);
+
+    //Clients predict their own movement, the host is already authoritative
+    if !state.hosting {
+        state.prediction.record(input);
+    }
+    if let Some(player) = state.players.get_mut(&own) {
+        player.position = pos;
+    }
     drop(state);
 }
 
+//Movement keys held this frame
+fn read_input(delta: f32) -> MoveInput {
+    let mut input = MoveInput {
+        seq: 0,
+        x: 0.0,
+        y: 0.0,
+        sprint: is_key_down(KeyCode::LeftShift),
+        dt: delta,
+    };
+
+    if is_key_down(KeyCode::A) {
+        input.x -= 1.0;
+    }
+
+    if is_key_down(KeyCode::D) {
+        input.x += 1.0;
+    }
+
+    if is_key_down(KeyCode::W) {
+        input.y -= 1.0;
+    }
+
+    if is_key_down(KeyCode::S) {
+        input.y += 1.0;
+    }
+    input
+}
+
+//Move a spectator's camera, which goes anywhere at player speed
+fn move_camera(camera: &mut Vec2, input: &MoveInput) {
+    let mut speed = movement::BASE_SPEED * input.dt;
+    if input.sprint {
+        speed *= movement::SPRINT_MULTIPLIER;
+    }
+    *camera += Vec2::new(input.x, input.y).normalize_or_zero() * speed;
+}
+
+//Minutes and seconds, for the replay position
+fn format_time(ms: u64) -> String {
+    format!("{}:{:04.1}", ms / 60000, (ms % 60000) as f32 / 1000.0)
+}
+
+//Play a recording through the same drawing as a live game, with controls for moving around in it
+async fn watch_replay(records: Vec<Record>, state_lock: &Arc<Mutex<GameState>>, game: &mut GameObject) {
+    let spawn = state_lock.lock().unwrap().spawn;
+    let mut replay = Replay::new(records, spawn);
+    //For watching a recorded spectator
+    let mut camera = spawn.to_vec2();
+
+    loop {
+        let delta = frame().full_frame_time;
+
+        //Played into the same state a client fills in, then drawn from it the same way
+        {
+            let mut state = state_lock.lock().unwrap();
+            if is_key_pressed(KeyCode::Space) {
+                replay.paused = !replay.paused;
+            }
+            if is_key_pressed(KeyCode::Up) {
+                replay.speed = (replay.speed * 2.0).min(MAX_REPLAY_SPEED);
+            }
+            if is_key_pressed(KeyCode::Down) {
+                replay.speed = (replay.speed / 2.0).max(MIN_REPLAY_SPEED);
+            }
+            if is_key_pressed(KeyCode::Tab) {
+                replay.view_next(&mut state);
+            }
+            let now = replay.time_ms() as f64;
+            if is_key_pressed(KeyCode::Left) {
+                replay.seek(now - REPLAY_SKIP_MS, &mut state);
+            }
+            if is_key_pressed(KeyCode::Right) {
+                replay.seek(now + REPLAY_SKIP_MS, &mut state);
+            }
+            //Click or drag along the bar at the bottom to jump there
+            let (mouse_x, mouse_y) = mouse_position();
+            if is_mouse_button_down(MouseButton::Left) && mouse_y >= screen_height() - REPLAY_BAR_HEIGHT * 2.0 {
+                let fraction = (mouse_x / screen_width()) as f64;
+                replay.seek(fraction * replay.length_ms() as f64, &mut state);
+            }
+            replay.advance(delta, &mut state);
+        }
+
+        game_from_state(game, state_lock);
+        if game.spectating {
+            move_camera(&mut camera, &read_input(delta));
+        }
+
+        clear_background(BLACK);
+        match &game.ready {
+            GameReadiness::Ready => {
+                let pos = match game.players.get(&game.own_player) {
+                    Some(p) if !game.spectating => p.position,
+                    _ => camera,
+                };
+                game.draw(pos);
+            }
+            GameReadiness::Error(er) => {
+                draw_text(er, screen_width() / 2.0 - 25.0, screen_height() / 2.0, 20.0, WHITE);
+            }
+            GameReadiness::Loading | GameReadiness::Reconnecting => {
+                let text = if replay.viewing().is_some() { "Waiting for the client to join" } else { "Nobody joined in this recording" };
+                draw_text(text, screen_width() / 2.0 - 120.0, screen_height() / 2.0, 20.0, WHITE);
+            }
+        }
+
+        //Where playback is, and who it's showing
+        let mut status = format!(
+            "Replay {} / {}  x{}",
+            format_time(replay.time_ms()),
+            format_time(replay.length_ms()),
+            replay.speed
+        );
+        if replay.paused {
+            status += "  (paused)";
+        }
+        if let Some((index, count)) = replay.viewing() {
+            let name = match game.players.get(&game.own_player) {
+                Some(p) if !game.spectating => p.name.clone(),
+                _ => String::from("a spectator"),
+            };
+            status += &format!("  Watching client {} of {}, {}", index + 1, count, name);
+        }
+        draw_text(&status, 10.0, 20.0, 20.0, WHITE);
+        draw_text(
+            "Space pause, Left/Right skip 5s, Up/Down speed, Tab next client, click the bar to seek",
+            10.0,
+            40.0,
+            16.0,
+            GRAY,
+        );
+
+        let length = replay.length_ms().max(1) as f32;
+        let bar_y = screen_height() - REPLAY_BAR_HEIGHT;
+        draw_rectangle(0.0, bar_y, screen_width(), REPLAY_BAR_HEIGHT, DARKGRAY);
+        draw_rectangle(0.0, bar_y, screen_width() * replay.time_ms() as f32 / length, REPLAY_BAR_HEIGHT, WHITE);
+
+        next_frame().await;
+    }
+}
+
 #[macroquad::main("MacroProx")]
 async fn main() {
 
     //Initial things
     set_pc_assets_folder("assets");
-    const BASESPEED:f32 = 250.0;
 
     // let font = load_ttf_font("./assets/fonts/Raleway-SemiBold.ttf").await.unwrap();
 
     let mut settings = GameSettings {
         ..Default::default()
     };
+    parse_netsim_args(&settings.netsim);
     main_menu(&mut settings).await;
 
 
@@ -150,10 +402,15 @@ async fn main() {
         },
         ..Default::default()
     };
-    let mut state_lock = Arc::new(Mutex::new(game_state));
+    let state_lock = Arc::new(Mutex::new(game_state));
     let thread_mutex = Arc::clone(&state_lock);
+    //Stops the server if this process is hosting, as a client that took over or from the start
+    let shutdown = Arc::clone(&settings.shutdown);
+    //Also kept by the client for the game it takes over, so the overlay works after a host migration
+    let netsim = Arc::clone(&settings.netsim);
+    let mut show_netsim = false;
 
-    match settings.game_type {
+    let network = match settings.game_type {
         GameType::Host => {
             //Add first player
             let me = NetPlayer {
@@ -164,50 +421,64 @@ async fn main() {
             };
 
             load_game_map(&state_lock, &me).await;
+            state_lock.lock().unwrap().hosting = true;
+            let server_settings = server::ServerSettings {
+                address: address::any_address(settings.port),
+                name: format!("{}'s game", settings.player_name),
+                max_players: settings.max_players,
+                timeout: Duration::from_secs(settings.timeout_secs),
+                password: (!settings.password.is_empty()).then(|| settings.password.clone()),
+                shutdown: Arc::clone(&settings.shutdown),
+                record: settings.record.clone(),
+                netsim: Arc::clone(&settings.netsim),
+                ..Default::default()
+            };
             //Start host
             thread::spawn(move || {
-                server::run_host(thread_mutex);
-            });
+                server::run_host(thread_mutex, server_settings);
+            })
+        }
+        GameType::Replay => {
+            let records = std::mem::take(&mut settings.replay);
+            watch_replay(records, &state_lock, &mut game).await;
+            return;
         }
         GameType::Client => {
 
             //Start client
-            thread::spawn(move || {
+            let network = thread::spawn(move || {
                 client::run_client(settings, thread_mutex);
             });
             load_client(&state_lock).await;
+            network
         }
-    }
+    };
 
     //Start game
 
-    loop {
-        let delta = frame().full_frame_time;
-        game_from_state(&mut game, &state_lock); //Load latest state to game
+    //Spectators have no player to follow, so look from here instead, and players from where theirs last was
+    let mut camera = state_lock.lock().unwrap().spawn.to_vec2();
 
-        let mut speed = BASESPEED * delta;
-        let mut movement_vec = Vec2 { x: 0.0, y: 0.0 };
-
-        //Sprint
-        if is_key_down(KeyCode::LeftShift) {
-            speed *= 2.0;
-        }
-
-        if is_key_down(KeyCode::A) {
-            movement_vec.x -= 1.0;
-        }
-
-        if is_key_down(KeyCode::D) {
-            movement_vec.x += 1.0;
+    //Closing is handled below, so a host can hand the game over first
+    prevent_quit();
+    loop {
+        if is_quit_requested() {
+            //Server tells everyone who's taking over, then stops
+            if state_lock.lock().unwrap().hosting {
+                shutdown.store(true, Ordering::Relaxed);
+                let _ = network.join();
+            }
+            return;
         }
 
-        if is_key_down(KeyCode::W) {
-            movement_vec.y -= 1.0;
-        }
+        let input = read_input(frame().full_frame_time);
 
-        if is_key_down(KeyCode::S) {
-            movement_vec.y += 1.0;
+        if game.spectating {
+            move_camera(&mut camera, &input);
+        } else {
+            move_own_player(&state_lock, input);
         }
+        game_from_state(&mut game, &state_lock); //Load latest state to game
 
         match (&mut game).ready {
             //Check for any errors
@@ -222,29 +493,48 @@ async fn main() {
                 );
             }
             GameReadiness::Loading => (),
+            GameReadiness::Reconnecting => {
+                //Keep showing the world as it was, it carries on once reconnected
+                clear_background(BLACK);
+                if game.spectating {
+                    game.draw(camera);
+                } else if let Some(p) = game.players.get(&game.own_player) {
+                    game.draw(p.position);
+                }
+                draw_text("Reconnecting...", screen_width() / 2.0 - 60.0, 40.0, 25.0, WHITE);
+            }
             GameReadiness::Ready => {
                 // println!("I am {} out of {} players", game.own_player, game.players.len());
-                let player = game.players.get(&game.own_player).unwrap();
-                //Normalise movement to ensure diagonal movement speed is the same as other directions
-                movement_vec = movement_vec.normalize();
-                let mut pos = player.position;
-                if !movement_vec.is_nan() {
-                    pos += movement_vec * speed;
-                }
-
-                pos = game.resolve_collide(player, pos);
+                //Own player can be missing for a moment after reconnecting or moving host, stay where it last was
+                let pos = match game.players.get(&game.own_player) {
+                    Some(p) if !game.spectating => {
+                        camera = p.position;
+                        p.position
+                    }
+                    _ => camera,
+                };
                 // game.resolve_audio();
-                let player = game.players.get_mut(&game.own_player).unwrap();
-                player.position = pos;
 
                 clear_background(BLACK);
                 game.draw(pos);
+                if game.spectating {
+                    draw_text("Spectating", screen_width() / 2.0 - 45.0, 40.0, 25.0, WHITE);
+                }
 
-                //Write new changes to state
-                own_changes_to_state(&game, &mut state_lock);
+                if let Some(link) = game.link {
+                    let text = format!("RTT {:.0} ms (jitter {:.0} ms)", link.rtt_ms, link.jitter_ms);
+                    draw_text(&text, 10.0, 20.0, 20.0, WHITE);
+                }
             }
         }
 
+        if is_key_pressed(KeyCode::F3) {
+            show_netsim = !show_netsim;
+        }
+        if show_netsim {
+            draw_netsim_overlay(&netsim);
+        }
+
         next_frame().await;
     }
 }
@@ -2,53 +2,53 @@
 
 use std::sync::{Arc, Mutex};
 
-use macroquad::color::{ORANGE, WHITE};
+use crate::{game::{GameReadiness, GameState}, net_common::{NetBuilding, NetColour, NetPosition}};
 
-use crate::{game::{GameReadiness, GameState}, net_common::{NetBuilding, NetColour, NetPlayer, NetPosition}};
+//Same values as the macroquad colours, so the map can be loaded without graphics
+const WHITE: NetColour = NetColour { r: 1.0, g: 1.0, b: 1.0, a: 1.0 };
+const ORANGE: NetColour = NetColour { r: 1.0, g: 0.63, b: 0.0, a: 1.0 };
 
-pub fn load_map_1(state_lock: &Arc<Mutex<GameState>>, player: &NetPlayer) {
+//Load map buildings into state, players are added separately
+pub fn load_map_1(state_lock: &Arc<Mutex<GameState>>) {
     let mut game = state_lock.lock().unwrap();
+    game.map_name = "Map 1".to_string();
 
-    let mut p = player.clone();
-    p.position = game.spawn;
-
-    game.players.insert(player.id, p);
     //TODO: Make generic eventually
     game.buildings.push(NetBuilding {
         position: NetPosition { x: 5.0, y: 5.0 },
         width: 50.0,
         height: 20.0,
-        colour: NetColour::from_col(WHITE),
+        colour: WHITE,
     });
     game.buildings.push(NetBuilding {
         position: NetPosition { x: 798.0, y: 15.0 },
         width: 80.0,
         height: 10.0,
-        colour: NetColour::from_col(ORANGE),
+        colour: ORANGE,
     });
     game.buildings.push(NetBuilding {
         position: NetPosition { x: 436.0, y: 70.0 },
         width: 100.0,
         height: 54.0,
-        colour: NetColour::from_col(WHITE),
+        colour: WHITE,
     });
     game.buildings.push(NetBuilding {
         position: NetPosition { x: 55.0, y: 58.0 },
         width: 10.0,
         height: 68.0,
-        colour: NetColour::from_col(WHITE),
+        colour: WHITE,
     });
     game.buildings.push(NetBuilding {
         position: NetPosition { x: 846.0, y: 375.0 },
         width: 90.0,
         height: 24.0,
-        colour: NetColour::from_col(WHITE),
+        colour: WHITE,
     });
     game.buildings.push(NetBuilding {
         position: NetPosition { x: 600.0, y: 458.0 },
         width: 120.0,
         height: 14.0,
-        colour: NetColour::from_col(WHITE),
+        colour: WHITE,
     });
     game.buildings.push(NetBuilding {
         position: NetPosition {
@@ -57,13 +57,13 @@ pub fn load_map_1(state_lock: &Arc<Mutex<GameState>>, player: &NetPlayer) {
         },
         width: 200.0,
         height: 19.0,
-        colour: NetColour::from_col(WHITE),
+        colour: WHITE,
     });
     game.buildings.push(NetBuilding {
         position: NetPosition { x: 20.0, y: 79.0 },
         width: 205.0,
         height: 94.0,
-        colour: NetColour::from_col(WHITE),
+        colour: WHITE,
     });
 
     // let mut sounds: Vec<(Vec2, _)> = Vec::new(); //Try to load concurrently
@@ -0,0 +1,446 @@
+//Network in memory, for running a host and clients together on one thread and stepping them by hand
+//Connections, messages and timers are queued by when they're due, on a clock that only moves when the network is
+//stepped, so seconds of play take no time and come out the same every run. Every node shares one address space:
+//listeners are found by port whatever IP is connected to, and each node gets its own IP to be seen from.
+
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+use message_io::network::{
+    Endpoint, NetEvent, ResourceId, ResourceType, Transport, TransportConnect, TransportListen,
+};
+use message_io::node::NodeEvent;
+
+use crate::clock;
+use crate::transport::Network;
+
+//Ports handed out for outgoing connections start here
+const FIRST_EPHEMERAL_PORT: u16 = 40000;
+//Nodes are 10.0.0.1, 10.0.0.2 and so on
+const FIRST_NODE_IP: u32 = 0x0a00_0001;
+
+//Something that happens on a node
+enum Delivery {
+    Accepted(Endpoint, ResourceId),
+    Connected(Endpoint, bool),
+    Message(Endpoint, Vec<u8>),
+    Disconnected(Endpoint),
+    Signal(u64), //Key into the node's own signals, which each have their own type
+}
+
+//One end of a connection
+struct End {
+    node: usize,
+    peer_node: usize,
+    peer: Endpoint, //How the other end knows this connection
+}
+
+//UDP listener or socket
+struct Socket {
+    node: usize,
+    id: ResourceId,
+    connected_to: Option<SocketAddr>, //Sockets from connect only talk to one address
+}
+
+struct World {
+    start: Instant,
+    elapsed: Duration,
+    latency: Duration,
+    next_seq: u64, //Orders everything due at the same time by when it was queued
+    queue: BinaryHeap<Reverse<(Duration, u64)>>,
+    pending: HashMap<u64, (usize, Delivery)>,
+    running: Vec<bool>, //By node
+    next_id: usize,
+    next_port: u16,
+    tcp_listeners: HashMap<u16, (usize, ResourceId)>,
+    udp_ports: HashMap<u16, Socket>,
+    udp_ids: HashMap<ResourceId, u16>,
+    connections: HashMap<ResourceId, End>,
+    captured: HashMap<u16, Vec<Vec<u8>>>, //Datagrams sent to each captured port
+}
+
+impl World {
+    fn node_ip(node: usize) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(FIRST_NODE_IP + node as u32))
+    }
+
+    //Every resource looks like a UDP socket this side opened, the only kind message-io lets an Endpoint be made for
+    //Ids are laid out as the adapter in the low 7 bits, a bit set for local resources, then a number for each
+    fn new_id(&mut self) -> ResourceId {
+        let id = ResourceId::from(Transport::Udp.id() as usize | 1 << 7 | self.next_id << 8);
+        debug_assert!(id.resource_type() == ResourceType::Local && id.adapter_id() == Transport::Udp.id());
+        self.next_id += 1;
+        id
+    }
+
+    fn set_time(&mut self, elapsed: Duration) {
+        self.elapsed = elapsed;
+        clock::drive(Some(self.start + elapsed));
+    }
+
+    fn schedule(&mut self, node: usize, wait: Duration, delivery: Delivery) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.queue.push(Reverse((self.elapsed + wait, seq)));
+        self.pending.insert(seq, (node, delivery));
+    }
+
+    //Over the network, rather than a node signalling itself
+    fn deliver(&mut self, node: usize, delivery: Delivery) {
+        let latency = self.latency;
+        self.schedule(node, latency, delivery);
+    }
+
+    fn free_port(&mut self, port: u16, udp: bool) -> io::Result<u16> {
+        let taken = |world: &World, port| {
+            if udp {
+                world.udp_ports.contains_key(&port)
+            } else {
+                world.tcp_listeners.contains_key(&port)
+            }
+        };
+        if port != 0 {
+            if taken(self, port) {
+                return Err(io::Error::from(io::ErrorKind::AddrInUse));
+            }
+            return Ok(port);
+        }
+        loop {
+            let port = self.next_port;
+            self.next_port = self.next_port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+            if !taken(self, port) {
+                return Ok(port);
+            }
+        }
+    }
+
+    fn connect(&mut self, node: usize, transport: TransportConnect, address: SocketAddr) -> io::Result<(Endpoint, SocketAddr)> {
+        let port = self.free_port(0, true)?;
+        let local = SocketAddr::new(World::node_ip(node), port);
+        let id = self.new_id();
+        let endpoint = Endpoint::from_listener(id, address);
+
+        if transport.id() == Transport::Udp.id() {
+            self.udp_ports.insert(port, Socket { node, id, connected_to: Some(address) });
+            self.udp_ids.insert(id, port);
+            self.schedule(node, Duration::ZERO, Delivery::Connected(endpoint, true));
+            return Ok((endpoint, local));
+        }
+
+        match self.tcp_listeners.get(&address.port()).copied() {
+            Some((server, listener)) => {
+                let accepted = Endpoint::from_listener(self.new_id(), local);
+                self.connections.insert(id, End { node, peer_node: server, peer: accepted });
+                self.connections.insert(accepted.resource_id(), End { node: server, peer_node: node, peer: endpoint });
+                self.deliver(server, Delivery::Accepted(accepted, listener));
+                self.deliver(node, Delivery::Connected(endpoint, true));
+            }
+            None => self.deliver(node, Delivery::Connected(endpoint, false)),
+        }
+        Ok((endpoint, local))
+    }
+
+    fn listen(&mut self, node: usize, transport: TransportListen, address: SocketAddr) -> io::Result<(ResourceId, SocketAddr)> {
+        let udp = transport.id() == Transport::Udp.id();
+        let port = self.free_port(address.port(), udp)?;
+        let id = self.new_id();
+        if udp {
+            self.udp_ports.insert(port, Socket { node, id, connected_to: None });
+            self.udp_ids.insert(id, port);
+        } else {
+            self.tcp_listeners.insert(port, (node, id));
+        }
+        Ok((id, SocketAddr::new(address.ip(), port)))
+    }
+
+    fn send(&mut self, node: usize, endpoint: Endpoint, data: &[u8]) {
+        let resource = endpoint.resource_id();
+        if let Some(end) = self.connections.get(&resource) {
+            let (peer_node, peer) = (end.peer_node, end.peer);
+            self.deliver(peer_node, Delivery::Message(peer, data.to_vec()));
+            return;
+        }
+
+        let Some(from_port) = self.udp_ids.get(&resource).copied() else {
+            return; //Closed
+        };
+        let Some(to) = self.udp_ports.get(&endpoint.addr().port()) else {
+            return; //Nobody there, the datagram is lost
+        };
+        if let Some(captured) = self.captured.get_mut(&endpoint.addr().port()) {
+            captured.push(data.to_vec());
+        }
+        let from = SocketAddr::new(World::node_ip(node), from_port);
+        let (to_node, received) = (to.node, Endpoint::from_listener(to.id, to.connected_to.unwrap_or(from)));
+        self.deliver(to_node, Delivery::Message(received, data.to_vec()));
+    }
+
+    fn remove(&mut self, resource: ResourceId) {
+        //The other end hears after whatever was sent before, anything it sends meanwhile goes nowhere
+        if let Some(end) = self.connections.remove(&resource) {
+            if self.connections.contains_key(&end.peer.resource_id()) {
+                self.deliver(end.peer_node, Delivery::Disconnected(end.peer));
+            }
+        }
+        if let Some(port) = self.udp_ids.remove(&resource) {
+            self.udp_ports.remove(&port);
+        }
+        self.tcp_listeners.retain(|_, (_, id)| *id != resource);
+    }
+
+    //Close everything a node had open, as if its process had gone
+    fn stop(&mut self, node: usize) {
+        self.running[node] = false;
+        let mut resources: Vec<ResourceId> = self
+            .connections
+            .iter()
+            .filter(|(_, end)| end.node == node)
+            .map(|(id, _)| *id)
+            .collect();
+        resources.extend(self.udp_ports.values().filter(|s| s.node == node).map(|s| s.id));
+        resources.extend(self.tcp_listeners.values().filter(|(n, _)| *n == node).map(|(_, id)| *id));
+        //Same order every run, whatever order the maps hold them in
+        resources.sort_by_key(ResourceId::raw);
+        for resource in resources {
+            self.remove(resource);
+        }
+    }
+
+    //Next delivery due by until, moving the clock to it
+    fn next(&mut self, until: Duration) -> Option<(usize, Delivery)> {
+        loop {
+            let Reverse((due, seq)) = *self.queue.peek()?;
+            if due > until {
+                return None;
+            }
+            self.queue.pop();
+            let (node, delivery) = self.pending.remove(&seq)?;
+            if !self.running[node] {
+                continue;
+            }
+            //Anything still on its way to a socket that's been closed is gone with it
+            let closed = match &delivery {
+                Delivery::Message(endpoint, _) => {
+                    let resource = endpoint.resource_id();
+                    !self.connections.contains_key(&resource) && !self.udp_ids.contains_key(&resource)
+                }
+                //This end goes once it hears, unless it was closed here first
+                Delivery::Disconnected(endpoint) => self.connections.remove(&endpoint.resource_id()).is_none(),
+                _ => false,
+            };
+            if closed {
+                continue;
+            }
+            self.set_time(due);
+            return Some((node, delivery));
+        }
+    }
+}
+
+type Handler = Box<dyn FnMut(Delivery)>;
+
+struct Shared {
+    world: RefCell<World>,
+    handlers: RefCell<Vec<Option<Handler>>>, //By node, taken out while one is running so it can use the network
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        clock::drive(None);
+    }
+}
+
+//The network, which owns everything running on it
+pub struct MemoryNetwork {
+    shared: Rc<Shared>,
+}
+
+impl MemoryNetwork {
+    //Messages take latency to arrive, each way. The clock on this thread follows the network from here on
+    pub fn new(latency: Duration) -> MemoryNetwork {
+        let start = clock::now();
+        let mut world = World {
+            start,
+            elapsed: Duration::ZERO,
+            latency,
+            next_seq: 0,
+            queue: BinaryHeap::new(),
+            pending: HashMap::new(),
+            running: Vec::new(),
+            next_id: 0,
+            next_port: FIRST_EPHEMERAL_PORT,
+            tcp_listeners: HashMap::new(),
+            udp_ports: HashMap::new(),
+            udp_ids: HashMap::new(),
+            connections: HashMap::new(),
+            captured: HashMap::new(),
+        };
+        world.set_time(Duration::ZERO);
+        MemoryNetwork {
+            shared: Rc::new(Shared {
+                world: RefCell::new(world),
+                handlers: RefCell::new(Vec::new()),
+            }),
+        }
+    }
+
+    //Network time so far
+    pub fn elapsed(&self) -> Duration {
+        self.shared.world.borrow().elapsed
+    }
+
+    //Copy every datagram sent to a port from now on, e.g for replaying them the way an attacker could
+    pub fn capture(&self, port: u16) {
+        self.shared.world.borrow_mut().captured.insert(port, Vec::new());
+    }
+
+    //Datagrams sent to a captured port so far, oldest first
+    pub fn captured(&self, port: u16) -> Vec<Vec<u8>> {
+        self.shared.world.borrow().captured.get(&port).cloned().unwrap_or_default()
+    }
+
+    //Add something to run on the network, e.g a host or client. start is given its connection to the network
+    //and returns what handles its events, or None if it couldn't start
+    pub fn spawn<S, F>(&self, start: impl FnOnce(MemoryNode<S>) -> Option<F>)
+    where
+        S: 'static,
+        F: FnMut(NodeEvent<S>) + 'static,
+    {
+        let node = {
+            let mut world = self.shared.world.borrow_mut();
+            world.running.push(true);
+            world.running.len() - 1
+        };
+        self.shared.handlers.borrow_mut().push(None);
+
+        let signals = Rc::new(RefCell::new(HashMap::new()));
+        let memory_node = MemoryNode {
+            shared: Rc::downgrade(&self.shared),
+            node,
+            signals: Rc::clone(&signals),
+        };
+        let Some(mut handle) = start(memory_node) else {
+            self.shared.world.borrow_mut().stop(node);
+            return;
+        };
+
+        let handler = move |delivery| {
+            let data;
+            let event = match delivery {
+                Delivery::Accepted(endpoint, listener) => NodeEvent::Network(NetEvent::Accepted(endpoint, listener)),
+                Delivery::Connected(endpoint, ok) => NodeEvent::Network(NetEvent::Connected(endpoint, ok)),
+                Delivery::Message(endpoint, message) => {
+                    data = message;
+                    NodeEvent::Network(NetEvent::Message(endpoint, &data))
+                }
+                Delivery::Disconnected(endpoint) => NodeEvent::Network(NetEvent::Disconnected(endpoint)),
+                Delivery::Signal(key) => match signals.borrow_mut().remove(&key) {
+                    Some(signal) => NodeEvent::Signal(signal),
+                    None => return,
+                },
+            };
+            handle(event);
+        };
+        self.shared.handlers.borrow_mut()[node] = Some(Box::new(handler));
+    }
+
+    //Handle everything due in the next duration of network time, in order
+    pub fn run_for(&self, duration: Duration) {
+        let until = self.elapsed() + duration;
+        loop {
+            let next = self.shared.world.borrow_mut().next(until);
+            let Some((node, delivery)) = next else {
+                break;
+            };
+            let handler = self.shared.handlers.borrow_mut()[node].take();
+            if let Some(mut handler) = handler {
+                handler(delivery);
+                //A node that stopped is dropped, along with whatever it was running
+                if self.shared.world.borrow().running[node] {
+                    self.shared.handlers.borrow_mut()[node] = Some(handler);
+                }
+            }
+        }
+        self.shared.world.borrow_mut().set_time(until);
+    }
+}
+
+//What one host or client on the network sends through
+pub struct MemoryNode<S> {
+    shared: Weak<Shared>, //Weak so the network can be dropped while its nodes still hold this
+    node: usize,
+    signals: Rc<RefCell<HashMap<u64, S>>>,
+}
+
+impl<S> MemoryNode<S> {
+    //The network this node is on, for starting something else on it, e.g a client taking over as host
+    pub fn network(&self) -> Option<MemoryNetwork> {
+        self.shared.upgrade().map(|shared| MemoryNetwork { shared })
+    }
+
+    fn with_world<T>(&self, f: impl FnOnce(&mut World) -> T) -> Option<T> {
+        let shared = self.shared.upgrade()?;
+        let mut world = shared.world.borrow_mut();
+        Some(f(&mut world))
+    }
+
+    fn queue_signal(&self, signal: S, wait: Duration) {
+        self.with_world(|world| {
+            self.signals.borrow_mut().insert(world.next_seq, signal);
+            world.schedule(self.node, wait, Delivery::Signal(world.next_seq));
+        });
+    }
+}
+
+impl<S> Clone for MemoryNode<S> {
+    fn clone(&self) -> MemoryNode<S> {
+        MemoryNode {
+            shared: Weak::clone(&self.shared),
+            node: self.node,
+            signals: Rc::clone(&self.signals),
+        }
+    }
+}
+
+fn gone() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "Network has been dropped")
+}
+
+impl<S> Network<S> for MemoryNode<S> {
+    fn send(&self, endpoint: Endpoint, data: &[u8]) {
+        self.with_world(|world| world.send(self.node, endpoint, data));
+    }
+
+    fn connect(&self, transport: TransportConnect, address: SocketAddr) -> io::Result<(Endpoint, SocketAddr)> {
+        self.with_world(|world| world.connect(self.node, transport, address)).unwrap_or_else(|| Err(gone()))
+    }
+
+    fn listen(&self, transport: TransportListen, address: SocketAddr) -> io::Result<(ResourceId, SocketAddr)> {
+        self.with_world(|world| world.listen(self.node, transport, address)).unwrap_or_else(|| Err(gone()))
+    }
+
+    fn is_datagram(&self, endpoint: Endpoint) -> bool {
+        self.with_world(|world| world.udp_ids.contains_key(&endpoint.resource_id())).unwrap_or(false)
+    }
+
+    fn remove(&self, resource: ResourceId) {
+        self.with_world(|world| world.remove(resource));
+    }
+
+    fn signal(&self, signal: S) {
+        self.queue_signal(signal, Duration::ZERO);
+    }
+
+    fn signal_after(&self, signal: S, wait: Duration) {
+        self.queue_signal(signal, wait);
+    }
+
+    fn stop(&self) {
+        self.with_world(|world| world.stop(self.node));
+    }
+}
@@ -1,19 +1,33 @@
 //Menus for the game
 //Men pages call each other recursively to go forward, unwind to go back
 
-use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::ops::Range;
-use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
 use macroquad::prelude::*;
 use macroquad::ui::{hash, root_ui};
 
-use crate::net_common::NetColour;
+use crate::discovery::LanBrowser;
+use crate::heartbeat::DEFAULT_TIMEOUT_SECS;
+use crate::interpolation::DEFAULT_DELAY_MS;
+use crate::net_common::{self, NetColour};
+use crate::netsim::SharedSim;
+use crate::replay::{self, Record};
+use crate::secure;
+use crate::server::DEFAULT_MAX_PLAYERS;
+use crate::wire;
+
+//How often to ask for LAN games while the connect menu is open
+const LAN_REFRESH_SECS: f64 = 2.0;
 
 //Type of game to start
 pub enum GameType {
     Host,
     Client,
+    Replay,
 }
 
 //Settings to be modified by menus
@@ -22,7 +36,18 @@ pub struct GameSettings {
     pub port: u16,
     pub player_name: String,
     pub player_colour: NetColour,
-    pub host: Option<Ipv4Addr>, //For use by client
+    pub host: Option<SocketAddr>, //Server to join, for use by client
+    pub host_name: String,      //Typed name of the server to join, looked up by the client if host isn't set
+    pub interp_delay_ms: u64,   //How far in the past other players are drawn, for use by client
+    pub max_players: u8,        //For use by host
+    pub timeout_secs: u64,      //Silence before a connection is given up on
+    pub password: String,       //Needed to join if hosting, given to the server if joining, empty for none
+    pub shutdown: Arc<AtomicBool>, //Set when the game is closing, stops any server this process is running
+    pub spectate: bool,         //Join without a player, for use by client
+    pub record: Option<PathBuf>, //File to record the game to, for use by host
+    pub replay: Vec<Record>,    //Recording to watch, for use by replay
+    pub netsim: SharedSim,      //Simulated network conditions, set from the command line or the overlay
+    pub known_servers: PathBuf, //Keys of servers joined before, to notice one changing
 }
 
 impl Default for GameSettings {
@@ -30,13 +55,31 @@ impl Default for GameSettings {
         GameSettings {
             game_type: GameType::Host,
             host: None,
+            host_name: String::new(),
             player_colour: NetColour::from_col(WHITE),
             player_name: String::from("Player 1"),
             port: 0,
+            interp_delay_ms: DEFAULT_DELAY_MS,
+            max_players: DEFAULT_MAX_PLAYERS,
+            timeout_secs: DEFAULT_TIMEOUT_SECS,
+            password: String::new(),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            spectate: false,
+            record: None,
+            replay: Vec::new(),
+            netsim: SharedSim::default(),
+            known_servers: PathBuf::from(secure::KNOWN_SERVERS_FILE),
         }
     }
 }
 
+//Cut a name being typed to the longest the server accepts
+fn limit_name(name: &mut String) {
+    if let Some((end, _)) = name.char_indices().nth(wire::MAX_NAME_LEN) {
+        name.truncate(end);
+    }
+}
+
 //Result returned by a menu
 pub struct MenuResult {
     //If true, the menu returning the value wants the previous menu to exit
@@ -46,7 +89,7 @@ pub struct MenuResult {
 
 //Main menu of the game at startup
 pub async fn main_menu(settings: &mut GameSettings) -> MenuResult {
-    let size = Vec2 { x: 200.0, y: 100.0 };
+    let size = Vec2 { x: 200.0, y: 130.0 };
     loop {
         let position = Vec2 {
             //Set position on screen
@@ -58,6 +101,7 @@ pub async fn main_menu(settings: &mut GameSettings) -> MenuResult {
 
         let mut set = false;
         let mut host = false;
+        let mut replay = false;
 
         root_ui().window(hash!(), position, size, |ui| {
             //Create buttons and check if they get clicked
@@ -69,6 +113,11 @@ pub async fn main_menu(settings: &mut GameSettings) -> MenuResult {
             if ui.button(None, "Connect") {
                 set = true;
             }
+
+            if ui.button(None, "Replay") {
+                set = true;
+                replay = true;
+            }
         });
 
         if set {
@@ -76,6 +125,8 @@ pub async fn main_menu(settings: &mut GameSettings) -> MenuResult {
             let ret: MenuResult;
             if host {
                 ret = host_game(settings).await;
+            } else if replay {
+                ret = replay_menu(settings).await;
             } else {
                 ret = client_game(settings).await;
             }
@@ -96,6 +147,9 @@ pub async fn host_game(settings: &mut GameSettings) -> MenuResult {
 
     let mut name = String::new();
     let mut port = String::from("5508");
+    let mut max_players = DEFAULT_MAX_PLAYERS.to_string();
+    let mut password = String::new();
+    let mut record = String::new();
     let mut col_r = 1.0 / 200.0;
     let mut col_g = 1.0 / 200.0;
     let mut col_b = 1.0 / 200.0;
@@ -107,6 +161,7 @@ pub async fn host_game(settings: &mut GameSettings) -> MenuResult {
         a: 1.0,
     };
     let mut portnum = port.parse::<u16>().unwrap();
+    let mut max_num = DEFAULT_MAX_PLAYERS;
     let mut ret = MenuResult { should_exit: true };
 
     loop {
@@ -128,7 +183,11 @@ pub async fn host_game(settings: &mut GameSettings) -> MenuResult {
             //Create buttons and check if they get clicked
 
             ui.input_text(hash!(), "Display Name", &mut name);
+            limit_name(&mut name);
             ui.input_text(hash!(), "Port", &mut port);
+            ui.input_text(hash!(), "Max Players", &mut max_players);
+            ui.input_password(hash!(), "Password (optional)", &mut password);
+            ui.input_text(hash!(), "Record To (optional)", &mut record);
             ui.separator();
             ui.separator();
             ui.separator();
@@ -194,6 +253,13 @@ pub async fn host_game(settings: &mut GameSettings) -> MenuResult {
             port = portnum.to_string();
         }
 
+        //Host counts as a player, so at least one
+        match max_players.parse::<u8>() {
+            Ok(t) if t > 0 => max_num = t,
+            _ if !max_players.is_empty() => max_players = max_num.to_string(),
+            _ => (),
+        }
+
         next_frame().await;
         if set {
             ret.should_exit = !back;
@@ -201,8 +267,12 @@ pub async fn host_game(settings: &mut GameSettings) -> MenuResult {
         }
     }
 
+    settings.port = portnum;
+    settings.max_players = max_num;
     settings.player_colour = col;
     settings.player_name = name;
+    settings.password = password;
+    settings.record = (!record.is_empty()).then(|| PathBuf::from(record));
     settings.game_type = GameType::Host;
 
     return ret;
@@ -215,6 +285,9 @@ pub async fn client_game(settings: &mut GameSettings) -> MenuResult {
     let mut name = String::new();
     let mut ip = String::from("127.0.0.1");
     let mut port = String::from("5508");
+    let mut delay = DEFAULT_DELAY_MS.to_string();
+    let mut password = String::new();
+    let mut spectate = false;
     let mut col_r = 1.0 / 200.0;
     let mut col_g = 1.0 / 200.0;
     let mut col_b = 1.0 / 200.0;
@@ -226,14 +299,36 @@ pub async fn client_game(settings: &mut GameSettings) -> MenuResult {
         a: 1.0,
     };
     let mut portnum = port.parse::<u16>().unwrap();
-    let mut address = Ipv4Addr::from_str(&ip).unwrap();
+    let mut delaynum = DEFAULT_DELAY_MS;
+    let mut address = None;
     let mut ret = MenuResult { should_exit: true };
 
+    //Servers on the local network, listed next to the manual entry
+    let browser = match LanBrowser::new() {
+        Ok(b) => Some(b),
+        Err(err) => {
+            println!("Could not search for LAN games: {}", err);
+            None
+        }
+    };
+    let mut last_refresh: Option<f64> = None;
+
     loop {
         let position = Vec2 {
-            x: screen_width() / 2.0 - size.x / 2.0,
+            x: screen_width() / 2.0 - size.x - 5.0,
             y: screen_height() / 2.0 - size.y / 2.0,
         };
+        let lan_position = Vec2 {
+            x: screen_width() / 2.0 + 5.0,
+            y: position.y,
+        };
+
+        if let Some(browser) = &browser {
+            if last_refresh.map_or(true, |t| get_time() - t > LAN_REFRESH_SECS) {
+                browser.refresh();
+                last_refresh = Some(get_time());
+            }
+        }
 
         col.r = col_r;
         col.g = col_g;
@@ -244,12 +339,66 @@ pub async fn client_game(settings: &mut GameSettings) -> MenuResult {
         let mut set = false;
         let mut back = false;
 
+        let mut join = None;
+        root_ui().window(hash!(), lan_position, size, |ui| {
+            ui.label(None, "LAN Games");
+            ui.separator();
+
+            let Some(browser) = &browser else {
+                ui.label(None, "Can't search this network");
+                return;
+            };
+            let servers = browser.servers();
+            if servers.is_empty() {
+                ui.label(None, "Searching...");
+            }
+            for server in servers {
+                let info = &server.info;
+                let mut text = format!("{} ({}/{}) {}", info.name, info.players, info.max_players, info.map);
+                if info.password {
+                    text += " (password)";
+                }
+                if info.protocol_version != net_common::PROTOCOL_VERSION {
+                    //Would only be rejected, so not clickable
+                    ui.label(None, &format!("{} (different version)", text));
+                } else if ui.button(None, text) {
+                    join = Some(server.address);
+                }
+            }
+
+            if ui.button(
+                Vec2 {
+                    x: 0.,
+                    y: size.y - 30.0,
+                },
+                "Refresh",
+            ) {
+                last_refresh = None;
+            }
+        });
+
+        //Joining from the list skips the manual entry
+        if let Some(server) = join {
+            ip = server.ip().to_string();
+            port = server.port().to_string();
+            if name.is_empty() {
+                name = String::from("Player 1");
+            }
+            address = Some(server);
+            portnum = server.port();
+            set = true;
+        }
+
         root_ui().window(hash!(), position, size, |ui| {
             //Create buttons and check if they get clicked
 
             ui.input_text(hash!(), "Display Name", &mut name);
-            ui.input_text(hash!(), "Host IP", &mut ip);
+            limit_name(&mut name);
+            ui.input_text(hash!(), "Host", &mut ip);
             ui.input_text(hash!(), "Port", &mut port);
+            ui.input_password(hash!(), "Password", &mut password);
+            ui.input_text(hash!(), "Interp Delay (ms)", &mut delay);
+            ui.checkbox(hash!(), "Spectate", &mut spectate);
             ui.separator();
             ui.separator();
             ui.separator();
@@ -302,16 +451,9 @@ pub async fn client_game(settings: &mut GameSettings) -> MenuResult {
                 },
                 "Apply",
             ) {
+                //Host is looked up once the client starts, names can take a while
                 if !ip.is_empty() && !name.is_empty() && !port.is_empty() {
-                    let s = Ipv4Addr::from_str(&ip);
-
-                    if s.is_ok() {
-                        //Check if ip valid first (port made valid while typing)
-                        address = s.unwrap();
-                        set = true;
-                    } else {
-                        ip = address.to_string();
-                    }
+                    set = true;
                 }
             }
         });
@@ -323,6 +465,12 @@ pub async fn client_game(settings: &mut GameSettings) -> MenuResult {
             port = portnum.to_string();
         }
 
+        if let Ok(t) = delay.parse::<u64>() {
+            delaynum = t;
+        } else if !delay.is_empty() {
+            delay = delaynum.to_string();
+        }
+
         next_frame().await;
         if set {
             ret.should_exit = !back;
@@ -331,10 +479,81 @@ pub async fn client_game(settings: &mut GameSettings) -> MenuResult {
     }
 
     settings.port = portnum;
+    settings.interp_delay_ms = delaynum;
     settings.player_colour = col;
     settings.player_name = name;
+    settings.password = password;
+    settings.spectate = spectate;
     settings.game_type = GameType::Client;
-    settings.host = Some(address);
+    settings.host = address;
+    settings.host_name = ip;
 
     return ret;
 }
+
+//Menu to pick a recording to watch
+pub async fn replay_menu(settings: &mut GameSettings) -> MenuResult {
+    let size = Vec2 { x: 300.0, y: 150.0 };
+
+    let mut file = String::new();
+    //Why the file couldn't be played, shown until the next try
+    let mut error: Option<String> = None;
+    let mut ret = MenuResult { should_exit: true };
+
+    loop {
+        let position = Vec2 {
+            x: screen_width() / 2.0 - size.x / 2.0,
+            y: screen_height() / 2.0 - size.y / 2.0,
+        };
+
+        clear_background(GRAY);
+
+        let mut set = false;
+        let mut back = false;
+
+        root_ui().window(hash!(), position, size, |ui| {
+            ui.input_text(hash!(), "Recording", &mut file);
+            if let Some(error) = &error {
+                ui.label(None, error);
+            }
+
+            if ui.button(
+                Vec2 {
+                    x: 0.,
+                    y: size.y - 30.0,
+                },
+                "Back",
+            ) {
+                set = true;
+                back = true;
+            }
+
+            if ui.button(
+                Vec2 {
+                    x: 80.0,
+                    y: size.y - 30.0,
+                },
+                "Watch",
+            ) && !file.is_empty()
+            {
+                match replay::load(Path::new(&file)) {
+                    Ok(records) => {
+                        settings.replay = records;
+                        set = true;
+                    }
+                    Err(err) => error = Some(err),
+                }
+            }
+        });
+
+        next_frame().await;
+        if set {
+            ret.should_exit = !back;
+            break;
+        }
+    }
+
+    settings.game_type = GameType::Replay;
+
+    ret
+}
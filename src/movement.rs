@@ -0,0 +1,227 @@
+//Player movement rules, shared by the server simulation and client prediction
+//Both sides must give the same result for the same input, so nothing here depends on graphics
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::net_common::{NetBuilding, NetPosition};
+
+pub const BASE_SPEED: f32 = 250.0;
+pub const SPRINT_MULTIPLIER: f32 = 2.0;
+//Longest time a single input can cover, so a stalled client can't jump in one step
+pub const MAX_INPUT_DT: f32 = 0.1;
+
+//Longest distance moved before checking collision, smaller than anything that can be collided with
+//so fast movement can't skip through thin buildings
+const MAX_STEP: f32 = 5.0;
+
+pub const PLAYER_WIDTH: f32 = 10.0;
+pub const PLAYER_HEIGHT: f32 = 20.0;
+
+//One frame of player input, numbered so the server can say which ones it has applied
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct MoveInput {
+    pub seq: u32,
+    //Direction held, normalised when applied
+    pub x: f32,
+    pub y: f32,
+    pub sprint: bool,
+    //Frame time in seconds
+    pub dt: f32,
+}
+
+impl MoveInput {
+    pub fn is_moving(&self) -> bool {
+        self.x != 0.0 || self.y != 0.0
+    }
+}
+
+//Last input the server has applied, and where that left the player
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct InputAck {
+    pub seq: u32,
+    pub pos: NetPosition,
+}
+
+//Get min and max extents of a player at a position
+fn player_min_max(pos: NetPosition) -> (NetPosition, NetPosition) {
+    let min = NetPosition {
+        x: pos.x,
+        y: pos.y + PLAYER_HEIGHT,
+    };
+    let max = NetPosition {
+        x: pos.x + PLAYER_WIDTH,
+        y: pos.y,
+    };
+
+    (min, max)
+}
+
+//Get min and max extents of a building
+fn building_min_max(b: &NetBuilding) -> (NetPosition, NetPosition) {
+    let min = NetPosition {
+        x: b.position.x,
+        y: b.position.y + b.height,
+    };
+    let max = NetPosition {
+        x: b.position.x + b.width,
+        y: b.position.y,
+    };
+
+    (min, max)
+}
+
+//Return position of player moving from one position to another after resolving collision
+//Will eventually return closer position if collision detected instead of 'cancelling'
+pub fn resolve_collide(buildings: &[NetBuilding], from: NetPosition, to: NetPosition) -> NetPosition {
+    //Calculate min and max extents of player
+    let (min_player, max_player) = player_min_max(to);
+    let mut ret_value = to;
+
+    for i in buildings {
+        let (min, max) = building_min_max(i);
+        let d1x = min.x - max_player.x;
+        let d1y = max_player.y - min.y;
+        let d2x = min_player.x - max.x;
+        let d2y = max.y - min_player.y;
+
+        //Check if definitely not colliding
+        if d1x > 0.0 || d1y > 0.0 {
+            continue;
+        }
+
+        if d2x > 0.0 || d2y > 0.0 {
+            continue;
+        }
+
+        //Check origin of movement
+
+        let (omin_player, omax_player) = player_min_max(from);
+
+        let od1x = min.x - omax_player.x;
+        let od1y = omax_player.y - min.y;
+        let od2x = omin_player.x - max.x;
+        let od2y = max.y - omin_player.y;
+
+        //If player was previously in x bounds safely, then it's the x change responsible for collision
+        //So disable x component, keep y (checked afterwards)
+        if od1x > 0.0 || od2x > 0.0 {
+            ret_value.x = from.x;
+        }
+
+        if od1y > 0.0 || od2y > 0.0 {
+            ret_value.y = from.y;
+        }
+
+        //Don't return value yet, disabling one movement component but keeping another could interfere with other object collisions
+    }
+
+    //Return requested position, possibly with some axis movement reset (for sliding along walls)
+    ret_value
+}
+
+//Move a player by one input, including collision
+pub fn apply_input(buildings: &[NetBuilding], pos: NetPosition, input: &MoveInput) -> NetPosition {
+    //Anything invalid from the network just means no movement
+    if !input.x.is_finite() || !input.y.is_finite() || !input.dt.is_finite() {
+        return pos;
+    }
+
+    //Normalise movement to ensure diagonal movement speed is the same as other directions
+    let len = (input.x * input.x + input.y * input.y).sqrt();
+    if len == 0.0 {
+        return pos;
+    }
+
+    let mut speed = BASE_SPEED * input.dt.clamp(0.0, MAX_INPUT_DT);
+    //Sprint
+    if input.sprint {
+        speed *= SPRINT_MULTIPLIER;
+    }
+
+    let steps = (speed / MAX_STEP).ceil().max(1.0);
+    let step_x = input.x / len * speed / steps;
+    let step_y = input.y / len * speed / steps;
+
+    let mut pos = pos;
+    for _ in 0..steps as u32 {
+        let to = NetPosition {
+            x: pos.x + step_x,
+            y: pos.y + step_y,
+        };
+        pos = resolve_collide(buildings, pos, to);
+    }
+    pos
+}
+
+//Client side prediction of own movement
+//Inputs are applied straight away, and kept until the server confirms them
+pub struct Prediction {
+    next_seq: u32,
+    last_sent: u32,
+    last_ack: u32,
+    pending: VecDeque<MoveInput>,
+}
+
+impl Prediction {
+    //Number an input and keep it until it's acknowledged
+    pub fn record(&mut self, mut input: MoveInput) -> MoveInput {
+        input.seq = self.next_seq;
+        self.next_seq += 1;
+        self.pending.push_back(input);
+        input
+    }
+
+    //Inputs not yet sent to the server
+    pub fn take_unsent(&mut self) -> Vec<MoveInput> {
+        let last_sent = self.last_sent;
+        let unsent: Vec<MoveInput> = self
+            .pending
+            .iter()
+            .filter(|i| i.seq > last_sent)
+            .copied()
+            .collect();
+
+        if let Some(last) = unsent.last() {
+            self.last_sent = last.seq;
+        }
+        unsent
+    }
+
+    //Most recent inputs the server hasn't confirmed, resent each time over UDP in case some were lost
+    pub fn unacked(&self, max: usize) -> Vec<MoveInput> {
+        let skip = self.pending.len().saturating_sub(max);
+        self.pending.iter().skip(skip).copied().collect()
+    }
+
+    //Start from the server's position and replay everything it hasn't seen yet
+    //Returns nothing if the ack is older than one already handled
+    pub fn reconcile(&mut self, buildings: &[NetBuilding], ack: &InputAck) -> Option<NetPosition> {
+        if ack.seq < self.last_ack {
+            return None;
+        }
+        self.last_ack = ack.seq;
+
+        while self.pending.front().is_some_and(|i| i.seq <= ack.seq) {
+            self.pending.pop_front();
+        }
+
+        let mut pos = ack.pos;
+        for input in &self.pending {
+            pos = apply_input(buildings, pos, input);
+        }
+        Some(pos)
+    }
+}
+
+impl Default for Prediction {
+    fn default() -> Prediction {
+        Prediction {
+            next_seq: 1, //0 means nothing acknowledged yet
+            last_sent: 0,
+            last_ack: 0,
+            pending: VecDeque::new(),
+        }
+    }
+}
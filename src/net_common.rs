@@ -1,10 +1,22 @@
-use crate::game::{self, Building, GameReadiness, Player};
+use crate::auth;
+use crate::game::{self, GameReadiness};
+use crate::movement::{InputAck, MoveInput};
+use crate::rate_limit::RateLimits;
+use crate::secure::Key;
+#[cfg(feature = "client")]
+use crate::game::{Building, Player};
+#[cfg(feature = "client")]
 use macroquad::{color::Color, math::Vec2};
 use serde::{Deserialize, Serialize};
-use std::{
-    net::Ipv4Addr,
-    sync::{Arc, Mutex},
-};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+//Bump whenever Commands or anything sent inside it changes
+pub const PROTOCOL_VERSION: u16 = 18;
+//Own player id given to spectators, never a real player's since ids stop below the player limit
+pub const SPECTATOR_ID: u8 = u8::MAX;
+//Commit the binary was built from, set by build.rs
+pub const BUILD_ID: &str = env!("MACROPROX_BUILD_ID");
 
 //Alternative to Vec2 which is sendable over threads and can be serialised for network
 #[derive(Serialize, Deserialize, Copy, Clone)]
@@ -14,10 +26,12 @@ pub struct NetPosition {
 }
 
 impl NetPosition {
+    #[cfg(feature = "client")]
     pub const fn from_vec2(vec: Vec2) -> NetPosition {
         NetPosition { x: vec.x, y: vec.y }
     }
 
+    #[cfg(feature = "client")]
     pub const fn to_vec2(&self) -> Vec2 {
         Vec2 {
             x: self.x,
@@ -40,6 +54,7 @@ pub struct NetColour {
     pub a: f32,
 }
 
+#[cfg(feature = "client")]
 impl NetColour {
     pub const fn from_col(col: Color) -> NetColour {
         NetColour {
@@ -68,6 +83,7 @@ pub struct NetBuilding {
     pub colour: NetColour,
 }
 
+#[cfg(feature = "client")]
 impl NetBuilding {
     pub const fn from_building(b: &Building) -> NetBuilding {
         NetBuilding {
@@ -89,10 +105,11 @@ impl NetBuilding {
 }
 
 //Info for client initialisation
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct NetPlayerInfo {
     pub players: Vec<NetPlayer>,
     pub your_num: u8,
+    pub reconnecting: Vec<u8>, //Players whose connection dropped, still holding their slot
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -103,6 +120,7 @@ pub struct NetPlayer {
     pub colour: NetColour,
 }
 
+#[cfg(feature = "client")]
 impl NetPlayer {
     pub fn from_player(p: &Player) -> NetPlayer {
         NetPlayer {
@@ -127,28 +145,159 @@ pub struct Map {
 }
 
 //Player id and position
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct PositionMap {
     pub id: u8,
     pub pos: NetPosition,
 }
 
+//Positions as of a server tick, stamped with the server time in milliseconds, packed by the snapshot module
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub tick: u32,
+    pub time: u64,
+    pub seq: u32,
+    pub base: u32, //Snapshot the data is relative to, 0 if it's complete
+    pub data: Vec<u8>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct RegistrationInfo {
+    //Version fields go first so they can be read even if the rest of the message can't
+    pub protocol_version: u16,
+    pub build_id: String,
     pub name: String,
     pub colour: NetColour,
+    pub session: Option<u64>, //Token from an earlier connection, to take back the same player
+    pub password: Option<auth::Proof>, //Answer to the server's challenge, if a password was given
+    pub host_key: Option<Key>, //Key the client would host with if it took over from a player host
+    pub spectator: bool, //Only watching, gets no player
+}
+
+//Who takes over if the player hosting the game leaves
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Successor {
+    pub id: u8,
+    pub host: u8, //Hosting player, who goes when the host does
+    pub address: SocketAddr, //As the host sees it, so loopback if it's on the host's machine
+    pub key: Key, //Key it will host with, so clients can trust it straight away
+    pub sessions: Vec<(auth::SessionDigest, u8)>, //Every other player's session digest and id, only sent to the successor
+    //Settings the game carries on with under the successor
+    pub max_players: u8,
+    pub rate_limits: RateLimits,
+    pub password: Option<String>, //Only sent to the successor
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum Commands {
+    //These two must stay first so clients and servers of any version can decode them
     RegisterPlayer(RegistrationInfo),
-    Move(NetPosition),
-    MovedPlayers(Vec<PositionMap>),
+    RejectRegistration(String),
+    Inputs(Vec<MoveInput>),
+    AckInput(InputAck),
+    MovedPlayers(Snapshot),
     SendMap(Map),
     SendPlayerInfo(NetPlayerInfo),
     AddPlayer(NetPlayer),
     RemovePlayer(u8),
     AllowClientReady(u8),
+    ClientReady, //Client has loaded everything and can be sent updates
+    BindUdp(u64), //Token tying a UDP address to a TCP session, sent over TCP then back over UDP
+    UdpBound,
+    Ping(u64), //Sender's clock in ms, echoed back in a Pong
+    Pong(u64),
+    Session(u64), //Token to send when reconnecting
+    Reconnecting(u8), //Player's connection dropped, they may come back
+    Reconnected(u8),
+    EnterInterest(PositionMap), //Player is now close enough to be sent to this client
+    LeaveInterest(u8),
+    AckSnapshot(u32), //Newest snapshot the client has, for the server to encode against
+    Challenge(u64), //Sent on connecting, registration has to answer it if the server has a password
+    Warning(String), //Client is doing something that will get it kicked if it carries on
+    Kick(String), //Sent just before the server drops the client, with why
+    Successor(Option<Successor>),
+    HostLeaving, //Player hosting is quitting, move to the successor
+}
+
+impl Commands {
+    //Name for logging, without the contents
+    pub fn name(&self) -> &'static str {
+        match self {
+            Commands::RegisterPlayer(_) => "RegisterPlayer",
+            Commands::RejectRegistration(_) => "RejectRegistration",
+            Commands::Inputs(_) => "Inputs",
+            Commands::AckInput(_) => "AckInput",
+            Commands::MovedPlayers(_) => "MovedPlayers",
+            Commands::SendMap(_) => "SendMap",
+            Commands::SendPlayerInfo(_) => "SendPlayerInfo",
+            Commands::AddPlayer(_) => "AddPlayer",
+            Commands::RemovePlayer(_) => "RemovePlayer",
+            Commands::AllowClientReady(_) => "AllowClientReady",
+            Commands::ClientReady => "ClientReady",
+            Commands::BindUdp(_) => "BindUdp",
+            Commands::UdpBound => "UdpBound",
+            Commands::Ping(_) => "Ping",
+            Commands::Pong(_) => "Pong",
+            Commands::Session(_) => "Session",
+            Commands::Reconnecting(_) => "Reconnecting",
+            Commands::Reconnected(_) => "Reconnected",
+            Commands::EnterInterest(_) => "EnterInterest",
+            Commands::LeaveInterest(_) => "LeaveInterest",
+            Commands::AckSnapshot(_) => "AckSnapshot",
+            Commands::Challenge(_) => "Challenge",
+            Commands::Warning(_) => "Warning",
+            Commands::Kick(_) => "Kick",
+            Commands::Successor(_) => "Successor",
+            Commands::HostLeaving => "HostLeaving",
+        }
+    }
+}
+
+//Wrapper for anything sent over UDP, numbered so late or duplicated datagrams can be dropped
+#[derive(Serialize, Deserialize)]
+pub struct Datagram {
+    pub seq: u32,
+    pub command: Commands,
+}
+
+//Sequence numbers for one end of a UDP channel
+#[derive(Default)]
+pub struct DatagramSeq {
+    sent: u32,
+    received: u32,
+}
+
+impl DatagramSeq {
+    //Serialise a command as the next datagram
+    pub fn wrap(&mut self, command: &Commands) -> Vec<u8> {
+        self.sent += 1;
+        //Same encoding as Datagram, without needing to own the command
+        bincode::serialize(&(self.sent, command)).unwrap()
+    }
+
+    //Whether a received datagram would be accepted, without accepting it
+    pub fn is_new(&self, seq: u32) -> bool {
+        seq > self.received
+    }
+
+    //Check a received datagram is newer than anything before it
+    pub fn accept(&mut self, seq: u32) -> bool {
+        if !self.is_new(seq) {
+            return false;
+        }
+        self.received = seq;
+        true
+    }
+}
+
+//Read the protocol version from a RegisterPlayer message, even if the rest can't be decoded
+pub fn peek_protocol_version(data: &[u8]) -> Option<u16> {
+    let (variant, version): (u32, u16) = bincode::deserialize(data).ok()?;
+    if variant == 0 {
+        Some(version)
+    } else {
+        None
+    }
 }
 
 //Set an error on game state
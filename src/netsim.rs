@@ -0,0 +1,252 @@
+//Simulated network conditions, for seeing how the game copes with links worse than localhost
+//Everything sent and received goes through here. Messages are held in a queue until they're due, going out
+//or being handed back to be handled then. Only datagrams are lost, duplicated or reordered: TCP never does
+//that, and the encryption on it would break if it did. TCP is only slowed down, and kept in order.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use message_io::network::Endpoint;
+use rand::Rng;
+
+use crate::clock;
+use crate::transport::Network;
+
+//Extra delay for a reordered datagram, enough for a few later ones to overtake it
+const REORDER_HOLD: Duration = Duration::from_millis(40);
+
+pub const USAGE: &str = "  --sim-latency <ms>   Simulated delay each way
+  --sim-jitter <ms>    Up to this much more delay, random for each message
+  --sim-loss <%>       Datagrams lost
+  --sim-duplicate <%>  Datagrams that arrive twice
+  --sim-reorder <%>    Datagrams held back so later ones overtake them
+  --sim-bandwidth <kbps>
+                       Most data a second each way, 0 for no limit";
+
+//What the link should be like, the same both ways
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Conditions {
+    pub latency_ms: u32,
+    pub jitter_ms: u32,
+    pub loss: f32, //Fractions from 0 to 1
+    pub duplicate: f32,
+    pub reorder: f32,
+    pub bandwidth_kbps: u32, //0 for no limit
+}
+
+impl Conditions {
+    //Whether everything goes straight through
+    pub fn is_perfect(&self) -> bool {
+        *self == Conditions::default()
+    }
+
+    //Set from a command line flag, returns false if it isn't one of these flags
+    pub fn parse_flag(&mut self, flag: &str, value: &str) -> Result<bool, String> {
+        let number = || value.parse::<u32>().map_err(|_| format!("Invalid value {} for {}", value, flag));
+        let percent = || match value.parse::<f32>() {
+            Ok(p) if (0.0..=100.0).contains(&p) => Ok(p / 100.0),
+            _ => Err(format!("{} needs a percentage from 0 to 100, got {}", flag, value)),
+        };
+        match flag {
+            "--sim-latency" => self.latency_ms = number()?,
+            "--sim-jitter" => self.jitter_ms = number()?,
+            "--sim-loss" => self.loss = percent()?,
+            "--sim-duplicate" => self.duplicate = percent()?,
+            "--sim-reorder" => self.reorder = percent()?,
+            "--sim-bandwidth" => self.bandwidth_kbps = number()?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+//What's been done to traffic so far
+#[derive(Clone, Copy, Default)]
+pub struct SimStats {
+    pub messages: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub queued: usize, //Held right now
+}
+
+//Shared with whatever sets the conditions, e.g the debug overlay
+#[derive(Default)]
+pub struct SimControls {
+    pub conditions: Conditions,
+    pub stats: SimStats,
+}
+
+pub type SharedSim = Arc<Mutex<SimControls>>;
+
+struct Held {
+    outbound: bool,
+    endpoint: Endpoint,
+    data: Vec<u8>,
+}
+
+//Queue for one host or client, sending through N, S is its signal type
+pub struct NetSim<S, N> {
+    net: N,
+    wake: fn() -> S, //Signal to send when something is due, which should call poll
+    controls: SharedSim,
+    queue: BinaryHeap<Reverse<(Instant, u64)>>, //Due time, then order held in for anything due at once
+    held: HashMap<u64, Held>,
+    next_id: u64,
+    woken_for: Option<Instant>, //Earliest time a wake signal is already on its way for
+    last_due: HashMap<(Endpoint, bool), Instant>, //Latest due time on each TCP connection, each way
+    link_free: [Instant; 2], //When the link is next free, out then in, for the bandwidth limit
+    handed_back: usize, //Received messages returned by poll and not handled yet
+}
+
+impl<S, N: Network<S>> NetSim<S, N> {
+    pub fn new(net: N, wake: fn() -> S, controls: SharedSim) -> NetSim<S, N> {
+        NetSim {
+            net,
+            wake,
+            controls,
+            queue: BinaryHeap::new(),
+            held: HashMap::new(),
+            next_id: 0,
+            woken_for: None,
+            last_due: HashMap::new(),
+            link_free: [clock::now(); 2],
+            handed_back: 0,
+        }
+    }
+
+    //Send now or once the conditions say it would arrive
+    pub fn send(&mut self, endpoint: Endpoint, data: &[u8]) {
+        if !self.hold(true, endpoint, data) {
+            self.net.send(endpoint, data);
+        }
+    }
+
+    //Returns true if the message has been held, in which case poll hands it back later
+    pub fn receive(&mut self, endpoint: Endpoint, data: &[u8]) -> bool {
+        self.hold(false, endpoint, data)
+    }
+
+    //Send anything due, and return received messages that are due to be handled, in order
+    //Call handled() for each once it has been
+    pub fn poll(&mut self) -> Vec<(Endpoint, Vec<u8>)> {
+        self.woken_for = None;
+        let now = clock::now();
+        //Order only matters against what's still held, which also forgets connections that have gone
+        self.last_due.retain(|_, due| *due > now);
+        let mut received = Vec::new();
+        while let Some(Reverse((due, id))) = self.queue.peek().copied() {
+            if due > now {
+                self.wake_at(due);
+                break;
+            }
+            self.queue.pop();
+            let Some(held) = self.held.remove(&id) else {
+                continue;
+            };
+            if held.outbound {
+                self.net.send(held.endpoint, &held.data);
+            } else {
+                received.push((held.endpoint, held.data));
+            }
+        }
+        self.handed_back += received.len();
+        self.controls.lock().unwrap().stats.queued = self.held.len();
+        received
+    }
+
+    //Send everything held for an endpoint straight away, before it's closed and anything still held is lost with it
+    pub fn flush(&mut self, endpoint: Endpoint) {
+        let mut ids: Vec<u64> = self
+            .held
+            .iter()
+            .filter(|(_, held)| held.outbound && held.endpoint == endpoint)
+            .map(|(id, _)| *id)
+            .collect();
+        //Held in the order they were sent
+        ids.sort();
+        for id in ids {
+            if let Some(held) = self.held.remove(&id) {
+                self.net.send(held.endpoint, &held.data);
+            }
+        }
+        self.controls.lock().unwrap().stats.queued = self.held.len();
+    }
+
+    pub fn handled(&mut self) {
+        self.handed_back = self.handed_back.saturating_sub(1);
+    }
+
+    fn hold(&mut self, outbound: bool, endpoint: Endpoint, data: &[u8]) -> bool {
+        let shared = Arc::clone(&self.controls);
+        let mut controls = shared.lock().unwrap();
+        let conditions = controls.conditions;
+        //Still queued if anything else is, so nothing overtakes what's already held
+        if conditions.is_perfect() && self.held.is_empty() && self.handed_back == 0 {
+            return false;
+        }
+        controls.stats.messages += 1;
+
+        let reliable = !self.net.is_datagram(endpoint);
+        let mut rng = rand::thread_rng();
+        let mut copies = 1;
+        if !reliable {
+            if rng.gen::<f32>() < conditions.loss {
+                controls.stats.lost += 1;
+                return true;
+            }
+            if rng.gen::<f32>() < conditions.duplicate {
+                controls.stats.duplicated += 1;
+                copies = 2;
+            }
+        }
+
+        let now = clock::now();
+        for _ in 0..copies {
+            let mut delay = Duration::from_millis(conditions.latency_ms as u64);
+            if conditions.jitter_ms > 0 {
+                delay += Duration::from_millis(rng.gen_range(0..=conditions.jitter_ms as u64));
+            }
+            if !reliable && rng.gen::<f32>() < conditions.reorder {
+                controls.stats.reordered += 1;
+                delay += REORDER_HOLD;
+            }
+            let mut due = now + delay;
+
+            //Queue behind whatever is already taking up the link
+            if conditions.bandwidth_kbps > 0 {
+                let link = &mut self.link_free[if outbound { 0 } else { 1 }];
+                let start = (*link).max(now);
+                let bits = data.len() as f64 * 8.0;
+                *link = start + Duration::from_secs_f64(bits / (conditions.bandwidth_kbps as f64 * 1000.0));
+                due = due.max(*link);
+            }
+
+            //TCP arrives in the order it was sent, however the delays fall
+            if reliable {
+                let last = self.last_due.entry((endpoint, outbound)).or_insert(due);
+                due = due.max(*last);
+                *last = due;
+            }
+
+            let id = self.next_id;
+            self.next_id += 1;
+            self.held.insert(id, Held { outbound, endpoint, data: data.to_vec() });
+            self.queue.push(Reverse((due, id)));
+            self.wake_at(due);
+        }
+        controls.stats.queued = self.held.len();
+        true
+    }
+
+    fn wake_at(&mut self, due: Instant) {
+        if self.woken_for.is_some_and(|w| w <= due) {
+            return;
+        }
+        self.woken_for = Some(due);
+        let wait = due.saturating_duration_since(clock::now());
+        self.net.signal_after((self.wake)(), wait);
+    }
+}
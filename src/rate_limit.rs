@@ -0,0 +1,290 @@
+//Flood protection for the server
+//Every client has a token bucket for all its messages together and one for each type of message. Messages over a
+//limit are dropped before they're handled, so a flood never gets near the game state. A client that keeps going
+//is warned, then kicked, and an address that keeps getting kicked is banned for a while. Only messages known to be
+//from the client count, anything else is limited by address with a SourceLimiter.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+
+//Time without going over a limit before a client's dropped messages are forgotten
+const FORGIVE: Duration = Duration::from_secs(10);
+//Most addresses tracked by a SourceLimiter, so forged addresses can't use up memory
+const MAX_SOURCES: usize = 4096;
+
+//Messages a second on average, and how many can arrive at once
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Limit {
+    pub rate: f32,
+    pub burst: f32,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct RateLimits {
+    pub total: Limit, //Every message from one client
+    pub updates: Limit, //Inputs and snapshot acks, which clients send every frame or tick
+    pub other: Limit, //Each other type of message
+    pub queries: Limit, //Status and discovery queries from one address
+    pub unauthenticated: Limit, //Datagrams from one address before they're known to be from a client
+    pub kick_after: u32, //Messages dropped before a warned client is kicked
+    pub ban_after: u32, //Kicks before an address is banned, 0 to never ban
+    pub ban_time: Duration,
+}
+
+impl Default for RateLimits {
+    fn default() -> RateLimits {
+        RateLimits {
+            total: Limit { rate: 400.0, burst: 400.0 },
+            updates: Limit { rate: 250.0, burst: 250.0 },
+            other: Limit { rate: 10.0, burst: 20.0 },
+            queries: Limit { rate: 5.0, burst: 10.0 },
+            unauthenticated: Limit { rate: 20.0, burst: 40.0 },
+            kick_after: 200,
+            ban_after: 3,
+            ban_time: Duration::from_secs(300),
+        }
+    }
+}
+
+impl RateLimits {
+    fn for_type(&self, name: &str) -> Limit {
+        match name {
+            "Inputs" | "AckSnapshot" => self.updates,
+            _ => self.other,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f32,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn new(limit: Limit) -> Bucket {
+        Bucket {
+            tokens: limit.burst,
+            last_refill: clock::now(),
+        }
+    }
+
+    //Whether it has refilled completely, so forgetting it would change nothing
+    fn is_full(&self, limit: Limit) -> bool {
+        self.tokens + clock::since(self.last_refill).as_secs_f32() * limit.rate >= limit.burst
+    }
+
+    //Take a token if there is one
+    fn take(&mut self, limit: Limit) -> bool {
+        let now = clock::now();
+        self.tokens = (self.tokens + (now - self.last_refill).as_secs_f32() * limit.rate).min(limit.burst);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+//What to do with a message
+pub enum Verdict {
+    Allow,
+    Drop,
+    Warn, //Drop it, and tell the client it's over the limit
+    Kick,
+}
+
+//Buckets for one client
+pub struct RateLimiter {
+    total: Bucket,
+    types: HashMap<&'static str, Bucket>,
+    dropped: u32,
+    last_drop: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &RateLimits) -> RateLimiter {
+        RateLimiter {
+            total: Bucket::new(limits.total),
+            types: HashMap::new(),
+            dropped: 0,
+            last_drop: clock::now(),
+        }
+    }
+
+    //Count a message against the limit for everything
+    pub fn check_total(&mut self, limits: &RateLimits) -> Verdict {
+        if self.total.take(limits.total) {
+            return Verdict::Allow;
+        }
+        self.offend(limits)
+    }
+
+    //Count a message against the limit for its type, by command name
+    pub fn check_type(&mut self, limits: &RateLimits, name: &'static str) -> Verdict {
+        let limit = limits.for_type(name);
+        let bucket = self.types.entry(name).or_insert_with(|| Bucket::new(limit));
+        if bucket.take(limit) {
+            return Verdict::Allow;
+        }
+        self.offend(limits)
+    }
+
+    fn offend(&mut self, limits: &RateLimits) -> Verdict {
+        if clock::since(self.last_drop) > FORGIVE {
+            self.dropped = 0;
+        }
+        self.last_drop = clock::now();
+        self.dropped += 1;
+
+        if self.dropped == 1 {
+            Verdict::Warn
+        } else if self.dropped >= limits.kick_after {
+            Verdict::Kick
+        } else {
+            Verdict::Drop
+        }
+    }
+}
+
+//A bucket for each address, for packets that can't be put down to a client, e.g queries from people who haven't joined
+//These are never warned, kicked or banned, the address could be forged to get someone else banned
+pub struct SourceLimiter {
+    limit: Limit,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl SourceLimiter {
+    pub fn new(limit: Limit) -> SourceLimiter {
+        SourceLimiter {
+            limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    //Count a packet from ip, returns whether it's within the limit
+    pub fn allow(&mut self, ip: IpAddr) -> bool {
+        let limit = self.limit;
+        if !self.buckets.contains_key(&ip) && self.buckets.len() >= MAX_SOURCES {
+            self.buckets.retain(|_, bucket| !bucket.is_full(limit));
+            //Everyone tracked is still sending, a newcomer waits rather than pushing one out
+            if self.buckets.len() >= MAX_SOURCES {
+                return false;
+            }
+        }
+        self.buckets.entry(ip).or_insert_with(|| Bucket::new(limit)).take(limit)
+    }
+}
+
+//Addresses that have been kicked, and those banned for it
+#[derive(Default)]
+pub struct Bans {
+    kicks: HashMap<IpAddr, (u32, Instant)>,
+    banned: HashMap<IpAddr, Instant>, //Until when
+}
+
+impl Bans {
+    //Record a kick, returns whether that gets the address banned
+    pub fn kicked(&mut self, ip: IpAddr, limits: &RateLimits) -> bool {
+        let now = clock::now();
+        //Kicks are forgotten after as long as a ban would last
+        self.kicks.retain(|_, (_, last)| now - *last < limits.ban_time);
+        let kicks = self.kicks.entry(ip).or_insert((0, now));
+        kicks.0 += 1;
+        kicks.1 = now;
+
+        if limits.ban_after == 0 || kicks.0 < limits.ban_after {
+            return false;
+        }
+        self.kicks.remove(&ip);
+        self.banned.insert(ip, now + limits.ban_time);
+        true
+    }
+
+    //How much longer an address is banned for, if it is
+    pub fn banned_for(&mut self, ip: IpAddr) -> Option<Duration> {
+        let now = clock::now();
+        self.banned.retain(|_, until| *until > now);
+        self.banned.get(&ip).map(|until| *until - now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    //Time stands still unless a test moves it
+    fn start() -> Instant {
+        let start = Instant::now();
+        clock::drive(Some(start));
+        start
+    }
+
+    fn allowed(limiter: &mut RateLimiter, limits: &RateLimits, count: u32) -> u32 {
+        (0..count).filter(|_| matches!(limiter.check_type(limits, "Ping"), Verdict::Allow)).count() as u32
+    }
+
+    #[test]
+    fn burst_then_exhaustion() {
+        start();
+        let limits = RateLimits::default();
+        let mut limiter = RateLimiter::new(&limits);
+        assert_eq!(allowed(&mut limiter, &limits, 20), 20);
+        assert!(matches!(limiter.check_type(&limits, "Ping"), Verdict::Warn));
+        assert!(matches!(limiter.check_type(&limits, "Ping"), Verdict::Drop));
+        //Other types have buckets of their own
+        assert!(matches!(limiter.check_type(&limits, "Inputs"), Verdict::Allow));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let start = start();
+        let limits = RateLimits::default();
+        let mut limiter = RateLimiter::new(&limits);
+        assert_eq!(allowed(&mut limiter, &limits, 21), 20);
+
+        clock::drive(Some(start + Duration::from_millis(500)));
+        assert_eq!(allowed(&mut limiter, &limits, 10), 5);
+        //Never past the burst, however long it's left
+        clock::drive(Some(start + Duration::from_secs(60)));
+        assert_eq!(allowed(&mut limiter, &limits, 30), 20);
+    }
+
+    #[test]
+    fn warns_then_kicks_then_bans() {
+        start();
+        let limits = RateLimits { kick_after: 3, ban_after: 2, ..Default::default() };
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut bans = Bans::default();
+        for kick in 1..=2 {
+            let mut limiter = RateLimiter::new(&limits);
+            allowed(&mut limiter, &limits, 20);
+            assert!(matches!(limiter.check_type(&limits, "Ping"), Verdict::Warn));
+            assert!(matches!(limiter.check_type(&limits, "Ping"), Verdict::Drop));
+            assert!(matches!(limiter.check_type(&limits, "Ping"), Verdict::Kick));
+            assert_eq!(bans.kicked(ip, &limits), kick == 2);
+        }
+        assert_eq!(bans.banned_for(ip), Some(limits.ban_time));
+        assert_eq!(bans.banned_for(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))), None);
+    }
+
+    #[test]
+    fn bans_expire() {
+        let start = start();
+        let limits = RateLimits { ban_after: 1, ..Default::default() };
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut bans = Bans::default();
+        assert!(bans.kicked(ip, &limits));
+
+        clock::drive(Some(start + limits.ban_time - Duration::from_secs(1)));
+        assert_eq!(bans.banned_for(ip), Some(Duration::from_secs(1)));
+        clock::drive(Some(start + limits.ban_time));
+        assert_eq!(bans.banned_for(ip), None);
+    }
+}
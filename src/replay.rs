@@ -0,0 +1,293 @@
+//Recording everything a server sends and receives, and playing it back as a client saw it
+//A recording is a header then one record per message, each with the time, which client and which way it went.
+//Messages are stored decrypted, since a replay has no keys, so recordings should be kept as private as the game.
+//Anything that would get someone holding a recording into the game is blanked out first, see redact.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use bincode::Options;
+use message_io::network::Endpoint;
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+use crate::game::{GameReadiness, GameState};
+use crate::net_common::{self, Commands, NetPosition, RegistrationInfo, Successor};
+use crate::snapshot::SnapshotReceiver;
+use crate::wire;
+
+const MAGIC: &[u8; 8] = b"MPREPLAY";
+
+//Variable length integers, most fields are small
+fn options() -> impl Options {
+    bincode::DefaultOptions::new().with_limit(wire::MAX_MESSAGE_BYTES)
+}
+
+#[derive(Serialize)]
+struct RecordRef<'a> {
+    time_ms: u64,
+    client: u32,
+    inbound: bool,
+    command: &'a Commands,
+}
+
+//One message, encoded the same as RecordRef
+#[derive(Deserialize)]
+pub struct Record {
+    pub time_ms: u64, //Since the server started
+    pub client: u32, //Numbered by connection, so a client that reconnects gets a new number
+    pub inbound: bool, //Sent by the client rather than to it
+    pub command: Commands,
+}
+
+//Copy of a command without secrets, if it has any: password proofs with the challenges they answer, which together
+//allow guessing the password offline, and session and UDP tokens, which could take over a player
+fn redact(command: &Commands) -> Option<Commands> {
+    let redacted = match command {
+        Commands::RegisterPlayer(info) => Commands::RegisterPlayer(RegistrationInfo {
+            protocol_version: info.protocol_version,
+            build_id: info.build_id.clone(),
+            name: info.name.clone(),
+            colour: info.colour,
+            session: info.session.map(|_| 0),
+            password: info.password.map(|_| Default::default()),
+            host_key: info.host_key,
+            spectator: info.spectator,
+        }),
+        Commands::Challenge(_) => Commands::Challenge(0),
+        Commands::Session(_) => Commands::Session(0),
+        Commands::BindUdp(_) => Commands::BindUdp(0),
+        Commands::Successor(Some(next)) => Commands::Successor(Some(Successor {
+            sessions: Vec::new(),
+            password: next.password.as_ref().map(|_| String::new()),
+            ..next.clone()
+        })),
+        _ => return None,
+    };
+    Some(redacted)
+}
+
+//Writes a recording for a running server
+pub struct Recorder {
+    file: Option<BufWriter<File>>, //Gone after a write fails
+    start: Instant,
+    clients: HashMap<Endpoint, u32>, //By TCP endpoint
+}
+
+impl Recorder {
+    //Times are from start, which should be what the server stamps snapshots against
+    pub fn create(path: &Path, start: Instant) -> io::Result<Recorder> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&net_common::PROTOCOL_VERSION.to_le_bytes())?;
+        Ok(Recorder {
+            file: Some(file),
+            start,
+            clients: HashMap::new(),
+        })
+    }
+
+    //Record a message to or from the client on this TCP endpoint
+    pub fn record(&mut self, endpoint: Endpoint, inbound: bool, command: &Commands) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let next = self.clients.len() as u32;
+        let redacted = redact(command);
+        let record = RecordRef {
+            time_ms: clock::since(self.start).as_millis() as u64,
+            client: *self.clients.entry(endpoint).or_insert(next),
+            inbound,
+            command: redacted.as_ref().unwrap_or(command),
+        };
+        if let Err(err) = options().serialize_into(file, &record) {
+            println!("Could not write to replay: {}, recording stopped", err);
+            self.file = None;
+        }
+    }
+
+    //Called every so often, so a server that's killed loses little
+    pub fn flush(&mut self) {
+        if let Some(file) = &mut self.file {
+            if let Err(err) = file.flush() {
+                println!("Could not write to replay: {}, recording stopped", err);
+                self.file = None;
+            }
+        }
+    }
+}
+
+//Read a whole recording, a record cut off at the end (e.g the server was killed) is left out
+pub fn load(path: &Path) -> Result<Vec<Record>, String> {
+    let file = File::open(path).map_err(|err| format!("Could not open {}: {}", path.display(), err))?;
+    let mut reader = BufReader::new(file);
+
+    let mut header = [0u8; 10];
+    reader.read_exact(&mut header).map_err(|_| String::from("Not a replay file"))?;
+    if &header[..8] != MAGIC {
+        return Err(String::from("Not a replay file"));
+    }
+    let version = u16::from_le_bytes([header[8], header[9]]);
+    if version != net_common::PROTOCOL_VERSION {
+        return Err(format!(
+            "Replay was recorded with protocol {}, this build plays protocol {}",
+            version,
+            net_common::PROTOCOL_VERSION
+        ));
+    }
+
+    let mut records = Vec::new();
+    while let Ok(record) = options().deserialize_from::<_, Record>(&mut reader) {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+//Plays a recording back into game state, as one of the recorded clients received it
+pub struct Replay {
+    records: Vec<Record>,
+    clients: Vec<u32>, //Clients that got into the world, in the order they joined
+    viewing: usize, //Index into clients
+    next: usize, //First record not applied yet
+    time_ms: f64,
+    pub speed: f64,
+    pub paused: bool,
+    snapshots: SnapshotReceiver,
+    spawn: NetPosition,
+}
+
+impl Replay {
+    pub fn new(records: Vec<Record>, spawn: NetPosition) -> Replay {
+        let mut clients = Vec::new();
+        for r in &records {
+            if !r.inbound && matches!(r.command, Commands::AllowClientReady(_)) && !clients.contains(&r.client) {
+                clients.push(r.client);
+            }
+        }
+        Replay {
+            records,
+            clients,
+            viewing: 0,
+            next: 0,
+            time_ms: 0.0,
+            speed: 1.0,
+            paused: false,
+            snapshots: SnapshotReceiver::default(),
+            spawn,
+        }
+    }
+
+    pub fn time_ms(&self) -> u64 {
+        self.time_ms as u64
+    }
+
+    pub fn length_ms(&self) -> u64 {
+        self.records.last().map_or(0, |r| r.time_ms)
+    }
+
+    //Which of the recorded clients is being watched, counting from 0, and how many there are to pick from
+    pub fn viewing(&self) -> Option<(usize, usize)> {
+        (!self.clients.is_empty()).then_some((self.viewing, self.clients.len()))
+    }
+
+    //Move playback on by dt seconds of real time
+    pub fn advance(&mut self, dt: f32, state: &mut GameState) {
+        if !self.paused {
+            self.time_ms = (self.time_ms + dt as f64 * 1000.0 * self.speed).min(self.length_ms() as f64);
+        }
+        self.play_to(state);
+    }
+
+    //Jump to a time, going back means starting again from the beginning
+    pub fn seek(&mut self, to_ms: f64, state: &mut GameState) {
+        let to_ms = to_ms.clamp(0.0, self.length_ms() as f64);
+        if to_ms < self.time_ms {
+            self.restart(state);
+        }
+        self.time_ms = to_ms;
+        self.play_to(state);
+    }
+
+    //Watch the next recorded client from the same point in time
+    pub fn view_next(&mut self, state: &mut GameState) {
+        if self.clients.is_empty() {
+            return;
+        }
+        self.viewing = (self.viewing + 1) % self.clients.len();
+        self.restart(state);
+        self.play_to(state);
+    }
+
+    fn restart(&mut self, state: &mut GameState) {
+        *state = GameState {
+            spawn: self.spawn,
+            ..Default::default()
+        };
+        self.next = 0;
+        self.snapshots = SnapshotReceiver::default();
+    }
+
+    fn play_to(&mut self, state: &mut GameState) {
+        let Some(&client) = self.clients.get(self.viewing) else {
+            return;
+        };
+        while let Some(record) = self.records.get(self.next) {
+            if record.time_ms as f64 > self.time_ms {
+                break;
+            }
+            self.next += 1;
+            if record.client != client || record.inbound {
+                continue;
+            }
+            //Interpolation runs on replay time, so it's smooth at any speed and stops when paused
+            state.interpolation.set_clock(record.time_ms);
+            apply(&record.command, &mut self.snapshots, state);
+        }
+        state.interpolation.set_clock(self.time_ms as u64);
+    }
+}
+
+//What the client does with a message from the server, minus anything it would send back
+fn apply(command: &Commands, snapshots: &mut SnapshotReceiver, state: &mut GameState) {
+    match command {
+        Commands::SendMap(map) => state.buildings = map.buildings.clone(),
+        Commands::SendPlayerInfo(player_info) => state.set_player_info(player_info.clone()),
+        Commands::AllowClientReady(id) => {
+            state.own_player = *id;
+            state.spectating = *id == net_common::SPECTATOR_ID;
+            state.ready = GameReadiness::Ready;
+        }
+        Commands::AddPlayer(player) => state.add_player(player.clone()),
+        Commands::RemovePlayer(id) => {
+            state.remove_player(*id);
+        }
+        Commands::Reconnecting(id) => {
+            state.reconnecting.insert(*id);
+        }
+        Commands::Reconnected(id) => {
+            state.reconnecting.remove(id);
+        }
+        Commands::EnterInterest(p) => state.enter_interest(p.clone()),
+        Commands::LeaveInterest(id) => state.leave_interest(*id),
+        Commands::MovedPlayers(dat) => {
+            if let Some(positions) = snapshots.receive(dat.seq, dat.base, &dat.data) {
+                state.apply_snapshot(dat.time, positions);
+            }
+        }
+        //The server's word on where the client's own player was, there are no inputs to predict from
+        Commands::AckInput(ack) => {
+            let own_player = state.own_player;
+            if let Some(p) = state.players.get_mut(&own_player) {
+                p.position = ack.pos;
+            }
+        }
+        Commands::Kick(reason) => state.ready = GameReadiness::Error(String::from("Kicked by server: ") + reason),
+        Commands::RejectRegistration(reason) => {
+            state.ready = GameReadiness::Error(String::from("Server rejected connection: ") + reason)
+        }
+        _ => (),
+    }
+}
@@ -0,0 +1,443 @@
+//Encrypted sessions between client and server
+//Every connection starts with a Noise_NX_25519_ChaChaPoly_SHA256 handshake (https://noiseprotocol.org/noise.html):
+//the client sends an ephemeral key, the server answers with its own ephemeral key and its long term static key.
+//Everything after is encrypted both ways, TCP with the keys Noise gives and UDP with a second pair from the same
+//handshake. The client remembers the server's static key the first time it connects (trust on first use) and
+//refuses to carry on if it ever changes, which is what stops someone in the middle pretending to be the server.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+pub type Key = [u8; 32];
+type Hash = [u8; 32];
+
+const PROTOCOL_NAME: &[u8; 32] = b"Noise_NX_25519_ChaChaPoly_SHA256";
+//Ties handshakes to this game, so they can't be mixed up with another protocol using the same pattern
+const PROLOGUE: &[u8] = b"macroprox";
+
+//Where clients keep the fingerprints of servers they've connected to
+pub const KNOWN_SERVERS_FILE: &str = "known_servers";
+pub const DEFAULT_KEY_FILE: &str = "server.key";
+//Poly1305 tag on the end of everything encrypted
+const TAG_LEN: usize = 16;
+
+//Hash of all the parts one after the other
+fn sha256(parts: &[&[u8]]) -> Hash {
+    let mut hash = Sha256::new();
+    for part in parts {
+        hash.update(part);
+    }
+    hash.finalize().into()
+}
+
+//Two keys from a chaining key and input key material, Noise's HKDF is RFC 5869's with no info
+fn hkdf2(chaining_key: &Key, input: &[u8]) -> (Key, Key) {
+    let mut output = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(chaining_key), input).expand(&[], &mut output).unwrap();
+    (output[..32].try_into().unwrap(), output[32..].try_into().unwrap())
+}
+
+//Noise style nonce, 4 zero bytes then the counter
+fn nonce_bytes(nonce: u64) -> [u8; 12] {
+    let mut bytes = [0u8; 12];
+    bytes[4..].copy_from_slice(&nonce.to_le_bytes());
+    bytes
+}
+
+//ChaCha20-Poly1305, returns the ciphertext with the tag on the end
+fn encrypt(key: &Key, nonce: u64, ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let cipher = ChaCha20Poly1305::new(key.into());
+    cipher.encrypt(&nonce_bytes(nonce).into(), Payload { msg: plaintext, aad: ad }).unwrap()
+}
+
+//Nothing if the data has been tampered with or the key is wrong
+fn decrypt(key: &Key, nonce: u64, ad: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(key.into());
+    cipher.decrypt(&nonce_bytes(nonce).into(), Payload { msg: data, aad: ad }).ok()
+}
+
+fn public_key(secret: &Key) -> Key {
+    x25519_dalek::x25519(*secret, x25519_dalek::X25519_BASEPOINT_BYTES)
+}
+
+fn random_key() -> Key {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Key> {
+    let text = text.trim();
+    if text.len() != 64 || !text.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+//Short form of a server's public key for people to compare
+pub fn fingerprint(public: &Key) -> String {
+    let hash = sha256(&[public]);
+    let groups: Vec<String> = hash[..16].chunks(2).map(hex).collect();
+    groups.join(":")
+}
+
+//Diffie-Hellman, nothing if the other side sent a key that gives a known result
+fn dh(secret: &Key, public: &Key) -> Option<Key> {
+    let shared = x25519_dalek::x25519(*secret, *public);
+    if shared.iter().all(|b| *b == 0) {
+        return None;
+    }
+    Some(shared)
+}
+
+//Server's long term key, clients pin its public half
+#[derive(Clone)]
+pub struct StaticKey {
+    secret: Key,
+    public: Key,
+}
+
+impl StaticKey {
+    pub fn generate() -> StaticKey {
+        let secret = random_key();
+        StaticKey {
+            secret,
+            public: public_key(&secret),
+        }
+    }
+
+    //Read the key from path, or make one and save it there if there isn't one yet
+    pub fn load_or_create(path: &Path) -> io::Result<StaticKey> {
+        match fs::read_to_string(path) {
+            Ok(text) => {
+                let secret = from_hex(&text).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a server key"))?;
+                Ok(StaticKey {
+                    secret,
+                    public: public_key(&secret),
+                })
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let key = StaticKey::generate();
+                let mut options = fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                //Only the owner should be able to read it
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                let mut file = options.open(path)?;
+                writeln!(file, "{}", hex(&key.secret))?;
+                Ok(key)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn public(&self) -> Key {
+        self.public
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public)
+    }
+}
+
+//Running hash and key of a handshake, as in section 5.2 of the Noise spec
+struct SymmetricState {
+    ck: Key,
+    h: Hash,
+    k: Option<Key>,
+    n: u64,
+}
+
+impl SymmetricState {
+    fn new() -> SymmetricState {
+        let mut state = SymmetricState {
+            ck: *PROTOCOL_NAME,
+            h: *PROTOCOL_NAME,
+            k: None,
+            n: 0,
+        };
+        state.mix_hash(PROLOGUE);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.h = sha256(&[&self.h, data]);
+    }
+
+    fn mix_key(&mut self, input: &Key) {
+        let (ck, k) = hkdf2(&self.ck, input);
+        self.ck = ck;
+        self.k = Some(k);
+        self.n = 0;
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = match &self.k {
+            Some(k) => encrypt(k, self.n, &self.h, plaintext),
+            None => plaintext.to_vec(),
+        };
+        self.n += 1;
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let plaintext = match &self.k {
+            Some(k) => decrypt(k, self.n, &self.h, ciphertext)?,
+            None => ciphertext.to_vec(),
+        };
+        self.n += 1;
+        self.mix_hash(ciphertext);
+        Some(plaintext)
+    }
+
+    //Keys for after the handshake, the first of each pair is for the client sending
+    fn split(&self, client: bool) -> Channel {
+        let (to_server, to_client) = hkdf2(&self.ck, &[]);
+        let (datagrams_to_server, datagrams_to_client) = hkdf2(&self.ck, b"datagrams");
+        let (send, recv, datagram_send, datagram_recv) = if client {
+            (to_server, to_client, datagrams_to_server, datagrams_to_client)
+        } else {
+            (to_client, to_server, datagrams_to_client, datagrams_to_server)
+        };
+        Channel {
+            send,
+            send_nonce: 0,
+            recv,
+            recv_nonce: 0,
+            datagram_send,
+            datagram_recv,
+            datagram_nonce: 0,
+        }
+    }
+}
+
+fn take_key(data: &[u8]) -> Option<(Key, &[u8])> {
+    if data.len() < 32 {
+        return None;
+    }
+    let (key, rest) = data.split_at(32);
+    Some((key.try_into().unwrap(), rest))
+}
+
+//Client half of a handshake in progress
+pub struct Handshake {
+    state: SymmetricState,
+    ephemeral: Key,
+}
+
+impl Handshake {
+    //Begin a handshake, returns it and the message to send the server
+    pub fn start() -> (Handshake, Vec<u8>) {
+        let mut state = SymmetricState::new();
+        let ephemeral = random_key();
+        let public = public_key(&ephemeral);
+
+        //-> e
+        state.mix_hash(&public);
+        let mut message = public.to_vec();
+        message.extend(state.encrypt_and_hash(&[]));
+        (Handshake { state, ephemeral }, message)
+    }
+
+    //Finish with the server's reply, returns the channel and the server's static key to check
+    pub fn finish(mut self, reply: &[u8]) -> Option<(Channel, Key)> {
+        let state = &mut self.state;
+
+        //<- e, ee, s, es
+        let (server_ephemeral, rest) = take_key(reply)?;
+        state.mix_hash(&server_ephemeral);
+        state.mix_key(&dh(&self.ephemeral, &server_ephemeral)?);
+        if rest.len() < 32 + TAG_LEN {
+            return None;
+        }
+        let (encrypted_static, payload) = rest.split_at(32 + TAG_LEN);
+        let server_static: Key = state.decrypt_and_hash(encrypted_static)?.try_into().ok()?;
+        state.mix_key(&dh(&self.ephemeral, &server_static)?);
+        state.decrypt_and_hash(payload)?;
+
+        Some((state.split(true), server_static))
+    }
+}
+
+//Server half of a handshake, returns the channel and the reply to send, or nothing if the request is bad
+pub fn accept(key: &StaticKey, request: &[u8]) -> Option<(Channel, Vec<u8>)> {
+    let mut state = SymmetricState::new();
+
+    //-> e
+    let (client_ephemeral, payload) = take_key(request)?;
+    state.mix_hash(&client_ephemeral);
+    //No payload is sent, anything else isn't a handshake from this game
+    if !state.decrypt_and_hash(payload)?.is_empty() {
+        return None;
+    }
+
+    //<- e, ee, s, es
+    let ephemeral = random_key();
+    let public = public_key(&ephemeral);
+    state.mix_hash(&public);
+    let mut reply = public.to_vec();
+    state.mix_key(&dh(&ephemeral, &client_ephemeral)?);
+    reply.extend(state.encrypt_and_hash(&key.public));
+    state.mix_key(&dh(&key.secret, &client_ephemeral)?);
+    reply.extend(state.encrypt_and_hash(&[]));
+
+    Some((state.split(false), reply))
+}
+
+//Keys for one end of an encrypted connection
+pub struct Channel {
+    send: Key,
+    send_nonce: u64,
+    recv: Key,
+    recv_nonce: u64,
+    datagram_send: Key,
+    datagram_recv: Key,
+    datagram_nonce: u64,
+}
+
+impl Channel {
+    //Encrypt a message for the reliable channel, which delivers everything in order so nonces are implicit
+    pub fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let data = encrypt(&self.send, self.send_nonce, &[], plaintext);
+        self.send_nonce += 1;
+        data
+    }
+
+    //Nothing if the message was tampered with, after which the connection can't be trusted
+    pub fn open(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        let plaintext = decrypt(&self.recv, self.recv_nonce, &[], data)?;
+        self.recv_nonce += 1;
+        Some(plaintext)
+    }
+
+    //Encrypt a datagram, these can be lost or reordered so the nonce goes in front
+    pub fn seal_datagram(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let mut data = self.datagram_nonce.to_le_bytes().to_vec();
+        data.extend(encrypt(&self.datagram_send, self.datagram_nonce, &[], plaintext));
+        self.datagram_nonce += 1;
+        data
+    }
+
+    //Replays aren't caught here, Datagram sequence numbers inside drop them
+    pub fn open_datagram(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < 8 {
+            return None;
+        }
+        let (nonce, ciphertext) = data.split_at(8);
+        let nonce = u64::from_le_bytes(nonce.try_into().unwrap());
+        decrypt(&self.datagram_recv, nonce, &[], ciphertext)
+    }
+}
+
+//What's known about a server's key
+pub enum Trust {
+    New,
+    Known,
+    Changed(String), //Fingerprint it had before
+}
+
+//Compare a server's fingerprint with the one saved from the last connection to it
+pub fn check_server(path: &Path, address: &str, fingerprint: &str) -> Trust {
+    let known = fs::read_to_string(path).unwrap_or_default();
+    for line in known.lines() {
+        let mut parts = line.split_whitespace();
+        if parts.next() == Some(address) {
+            return match parts.next() {
+                Some(old) if old == fingerprint => Trust::Known,
+                old => Trust::Changed(old.unwrap_or_default().to_string()),
+            };
+        }
+    }
+    Trust::New
+}
+
+//Save a server's fingerprint, replacing any it had before
+pub fn remember_server(path: &Path, address: &str, fingerprint: &str) -> io::Result<()> {
+    let known = fs::read_to_string(path).unwrap_or_default();
+    let mut kept: String = known
+        .lines()
+        .filter(|line| line.split_whitespace().next() != Some(address))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    kept += &format!("{} {}\n", address, fingerprint);
+    fs::write(path, kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(key: &StaticKey) -> (Channel, Channel, Key) {
+        let (handshake, request) = Handshake::start();
+        let (server, reply) = accept(key, &request).unwrap();
+        let (client, server_key) = handshake.finish(&reply).unwrap();
+        (client, server, server_key)
+    }
+
+    //RFC 7748 section 6.1, Alice's keys
+    #[test]
+    fn public_key_matches_rfc_7748() {
+        let secret = from_hex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a").unwrap();
+        let public = from_hex("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a").unwrap();
+        assert_eq!(public_key(&secret), public);
+    }
+
+    #[test]
+    fn handshake_gives_matching_channels() {
+        let key = StaticKey::generate();
+        let (mut client, mut server, server_key) = connect(&key);
+        assert_eq!(server_key, key.public());
+
+        for message in [&b"hello"[..], b"", b"again"] {
+            let sealed = client.seal(message);
+            assert_eq!(server.open(&sealed).unwrap(), message);
+            let sealed = server.seal(message);
+            assert_eq!(client.open(&sealed).unwrap(), message);
+        }
+        let datagram = server.seal_datagram(b"position");
+        assert_eq!(client.open_datagram(&datagram).unwrap(), b"position");
+    }
+
+    #[test]
+    fn tampered_messages_are_rejected() {
+        let (mut client, mut server, _) = connect(&StaticKey::generate());
+
+        let mut sealed = client.seal(b"hello");
+        *sealed.last_mut().unwrap() ^= 1; //Tag
+        assert!(server.open(&sealed).is_none());
+
+        let mut datagram = client.seal_datagram(b"position");
+        datagram[8] ^= 1; //Ciphertext
+        assert!(server.open_datagram(&datagram).is_none());
+
+        //A channel from another handshake can't read it either
+        let (_, mut other, _) = connect(&StaticKey::generate());
+        assert!(other.open(&client.seal(b"hello")).is_none());
+    }
+
+    #[test]
+    fn tampered_handshake_is_rejected() {
+        let key = StaticKey::generate();
+        let (handshake, request) = Handshake::start();
+        let (_, mut reply) = accept(&key, &request).unwrap();
+        reply[40] ^= 1; //Inside the encrypted static key
+        assert!(handshake.finish(&reply).is_none());
+    }
+}
@@ -1,211 +1,1413 @@
 use super::game;
 use super::net_common;
+use crate::address;
+use crate::auth::{self, SessionDigest};
+use crate::challenge::Challenges;
+use crate::clock;
+use crate::discovery::{self, ServerInfo};
 use crate::net_common::Commands;
+use crate::heartbeat::{self, Heartbeat};
+use crate::id_pool::IdPool;
+use crate::interest::{self, Interest};
+use crate::movement;
+use crate::movement::{InputAck, MoveInput};
+use crate::net_common::DatagramSeq;
 use crate::net_common::Map;
 use crate::net_common::NetPlayer;
-use crate::net_common::NetPosition;
 use crate::net_common::PositionMap;
-use message_io::network::Endpoint;
-use message_io::network::{NetEvent, Transport};
+use crate::net_common::RegistrationInfo;
+use crate::net_common::Snapshot;
+use crate::net_common::Successor;
+use crate::rate_limit::{Bans, Limit, RateLimiter, RateLimits, SourceLimiter, Verdict};
+use crate::memory_network::MemoryNetwork;
+use crate::netsim::{NetSim, SharedSim};
+use crate::replay::Recorder;
+use crate::secure::{self, Channel, Key, StaticKey};
+use crate::snapshot::{self, SnapshotSender, SnapshotState};
+use crate::status::{self, ServerStatus, StatusPlayer};
+use crate::tick::{self, TickClock};
+use crate::transport::Network;
+use crate::validation::MoveValidator;
+use crate::wire;
+use message_io::adapters::udp::UdpListenConfig;
+use message_io::network::{Endpoint, ResourceId};
+use message_io::network::{NetEvent, Transport, TransportListen};
 use message_io::node::NodeEvent;
 use message_io::node::{self};
 use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-// struct PlayerConnections {
-//     con_endpoint: Endpoint,
-// }
+//Server side info about a connected client
+struct PlayerConnection {
+    id: u8,
+    //Last input simulated for this player, and the last one the client was told about
+    last_input: u32,
+    acked_input: u32,
+    //Inputs waiting for the next tick
+    queued: Vec<MoveInput>,
+    validator: MoveValidator,
+    //UDP endpoint for position traffic, once the client has proved it owns the token
+    udp: Option<Endpoint>,
+    udp_token: u64,
+    udp_seq: DatagramSeq,
+    heartbeat: Heartbeat,
+    //Lets the client take this player back if the connection drops
+    session: u64,
+    interest: Interest,
+    snapshots: SnapshotSender,
+    //Key the client would host with, if it can take over from a player host
+    host_key: Option<Key>,
+    //Name, if this connection only watches and has no player
+    spectator: Option<String>,
+}
+
+impl PlayerConnection {
+    fn new(id: u8, session: u64, interest_radius: f32, host_key: Option<Key>) -> PlayerConnection {
+        PlayerConnection {
+            id,
+            session,
+            host_key,
+            spectator: None,
+            interest: Interest::new(interest_radius),
+            snapshots: SnapshotSender::new(),
+            last_input: 0,
+            acked_input: 0,
+            queued: Vec::new(),
+            validator: MoveValidator::new(),
+            udp: None,
+            udp_token: rand::random(),
+            udp_seq: DatagramSeq::default(),
+            heartbeat: Heartbeat::new(),
+        }
+    }
+
+    //Watcher that's sent every player, wherever they are
+    fn spectator(name: String) -> PlayerConnection {
+        let mut connection = PlayerConnection::new(net_common::SPECTATOR_ID, rand::random(), f32::INFINITY, None);
+        connection.spectator = Some(name);
+        connection
+    }
+}
+
+//Player whose connection dropped, kept in the game until the grace period runs out
+struct Suspended {
+    id: u8,
+    since: Instant,
+}
+
+//Where a connection is in joining the game, decides which messages it's allowed to send
+enum Connection {
+    //Connected at this time, the first message has to be the handshake
+    Handshaking(Instant),
+    //Encrypted since this time and sent this challenge, has to register before anything else
+    Accepted(Instant, u64),
+    //Has an id and has been sent the world, waiting for it to finish loading
+    Registered(PlayerConnection),
+    //Playing, gets position updates and can move
+    InWorld(PlayerConnection),
+}
+
+impl Connection {
+    fn player(&self) -> Option<&PlayerConnection> {
+        match self {
+            Connection::Handshaking(_) | Connection::Accepted(..) => None,
+            Connection::Registered(p) | Connection::InWorld(p) => Some(p),
+        }
+    }
+
+    fn player_mut(&mut self) -> Option<&mut PlayerConnection> {
+        match self {
+            Connection::Handshaking(_) | Connection::Accepted(..) => None,
+            Connection::Registered(p) | Connection::InWorld(p) => Some(p),
+        }
+    }
+
+    fn stage_name(&self) -> &'static str {
+        match self {
+            Connection::Handshaking(_) => "handshaking",
+            Connection::Accepted(..) => "accepted",
+            Connection::Registered(_) => "registered",
+            Connection::InWorld(_) => "in world",
+        }
+    }
+}
 
 enum Signal {
-    UpdateClients,
+    Tick,
+    Heartbeat,
+    NetSim, //Simulated network has messages due
 }
 
-//Run a listener for any new connections
-pub fn run_host(state_lock: Arc<Mutex<game::GameState>>) {
-    let (handler, listener) = node::split::<Signal>();
-    let mut clients: HashMap<Endpoint, u8> = HashMap::new();
-    let mut player_count = 1; //Start from index 1, since index 0 is own player
-
-    // Listen for TCP, UDP and WebSocket messages at the same time.
-    handler
-        .network()
-        .listen(Transport::FramedTcp, "0.0.0.0:5508")
-        .unwrap();
-    // handler
-    //     .network()
-    //     .listen(Transport::Udp, "0.0.0.0:3043")
-    //     .unwrap();
-
-    let mut positions: HashMap<u8, NetPosition> = HashMap::new();
-
-    handler
-        .signals()
-        .send_with_timer(Signal::UpdateClients, Duration::from_millis(500)); //Wait then send signal to start client update loop
-
-    println!("Running");
-    // Read incoming network events.
-    listener.for_each( move  |event| match event {
-        NodeEvent::Network(net_event) => match net_event {
-            NetEvent::Connected(_, _) => unreachable!(), // Used for explicit connections.
-            NetEvent::Accepted(_endpoint, _listener) => {
-                let state = state_lock.lock().unwrap();
-                println!("Client connected");
-                //TODO: Mutex?
-                clients.insert(_endpoint, player_count);
-                player_count += 1;
-                drop(state);
-                
-            }
-            NetEvent::Message(endpoint, data) => {
-                let res = bincode::deserialize(&data);
-
-                match res {
-                    Ok(dat) => {
-                        match dat {
-                            Commands::Move(dat) => {
-                                let mut state = state_lock.lock().unwrap();
-                                let player_num = clients.get(&endpoint).unwrap();
-                                let t = state.players.get_mut(player_num);
-                                match t {
-                                    None => {
-                                        println!("Trying to move non-existent player with unlinked endpoint!");
-                                    },
-                                    Some(player) => {
-                                        player.position.x = dat.x;
-                                        player.position.y = dat.y;
-                                    }
-                                }
-                                drop(state);
-                            }
-                            Commands::SendMap(_) => (), //Not for server
-                            Commands::SendPlayerInfo(_) => (), //Not for server
-                            Commands::AllowClientReady(_) => (), //Not for server
-                            Commands::MovedPlayers(_) => (), //Not for server
-                            Commands::AddPlayer(_) => (), //Not for server
-                            Commands::RemovePlayer(_) => (), //Not for server
-                            Commands::RegisterPlayer(player_info) => {
-                                
-                                println!("Attempting to register");
-                                let mut state = state_lock.lock().unwrap();
-                                let id = clients.get(&endpoint).unwrap();
-                                let new_player = NetPlayer {
-                                    colour: player_info.colour,
-                                    id: *id,
-                                    name: player_info.name,
-                                    position: state.spawn,
-                                };
-                                state.players.insert(*id, new_player.clone());
-
-                                //Serialise now to avoid borrowing issues
-                                let np_serial = bincode::serialize(&Commands::AddPlayer(new_player)).unwrap();
-                
-                                //Send info to new client               
-                                let mut players = Vec::new();
-                                for (_, p) in &state.players {
-                                    players.push((*p).clone());
-                                }
-                                let tosendb = bincode::serialize(&Commands::SendMap(Map { buildings: state.buildings.clone() })).unwrap();
-                                let tosendp =
-                                    bincode::serialize(&Commands::SendPlayerInfo(net_common::NetPlayerInfo {
-                                        players,//: state.players,
-                                        your_num: *id,
-                                    }))
-                                    .unwrap();
-                                //Serialised, now inc player count and release lock
-                                
-                                drop(state);
-                
-                                //Send map and players
-                                let _status = handler.network().send(endpoint, &tosendb);
-                                let _status = handler.network().send(endpoint, &tosendp);
-                
-                                //Update other clients
-                                for (c, _) in &clients {
-                                    if *c != endpoint {
-                                        let _status = handler.network().send(*c, &np_serial);                        
-                                    }
-                                }
-                
-                                //Client ready
-                                let tosend = bincode::serialize(&Commands::AllowClientReady(*id)).unwrap();
-                                let _status = handler.network().send(endpoint, &tosend);
-                            }, //Add a new player
-                        }
-                        // let state = state_lock.lock().unwrap();
-                        //TODO: Change position of specific player
-                        // state.position.x = dat.pX;
-                        // state.position.y = dat.pY;
-                        // drop(state);
+pub const DEFAULT_MAX_PLAYERS: u8 = 32;
+pub const DEFAULT_MAX_SPECTATORS: u8 = 8;
+pub const DEFAULT_RECONNECT_GRACE_SECS: u64 = 30;
+//Malformed messages a client can send before it's dropped, a working client never sends any
+const MAX_MALFORMED: u32 = 5;
+
+//Settings for running a host, either inside the game or as a dedicated server
+pub struct ServerSettings {
+    pub address: SocketAddr,
+    //Shown to players looking for a game
+    pub name: String,
+    //Answer LAN discovery queries
+    pub discoverable: bool,
+    //Clients have to prove they know this to join
+    pub password: Option<String>,
+    //Server's long term key, made on first run, clients warn if it changes
+    pub key_file: PathBuf,
+    //Key to host with instead of the key file, e.g one announced before taking over from another host
+    pub key: Option<StaticKey>,
+    //Players carried over from a host this one took over from, by session digest, held for them to reconnect
+    pub migrated: Vec<(SessionDigest, u8)>,
+    //If true, id 0 is taken by the player running the host
+    pub host_player: bool,
+    //Most players allowed at once, including the host's own player
+    pub max_players: u8,
+    pub max_spectators: u8,
+    //Set to true from another thread to stop the host
+    pub shutdown: Arc<AtomicBool>,
+    //Clients silent for this long are dropped, also the time allowed to register
+    pub timeout: Duration,
+    //How long a dropped player's slot is kept for them to reconnect
+    pub reconnect_grace: Duration,
+    //Clients are sent players within this distance every update, and a bit further occasionally
+    pub interest_radius: f32,
+    //Simulation and updates per second
+    pub tick_rate: u32,
+    //Flood protection thresholds
+    pub rate_limits: RateLimits,
+    //Record every message to and from clients in this file, to watch again later
+    pub record: Option<PathBuf>,
+    //Simulated latency, loss and so on, for testing
+    pub netsim: SharedSim,
+}
+
+impl Default for ServerSettings {
+    fn default() -> ServerSettings {
+        ServerSettings {
+            address: address::any_address(5508),
+            name: "MacroProx Server".to_string(),
+            discoverable: true,
+            password: None,
+            key_file: PathBuf::from(secure::DEFAULT_KEY_FILE),
+            key: None,
+            migrated: Vec::new(),
+            host_player: true,
+            max_players: DEFAULT_MAX_PLAYERS,
+            max_spectators: DEFAULT_MAX_SPECTATORS,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            timeout: Duration::from_secs(heartbeat::DEFAULT_TIMEOUT_SECS),
+            reconnect_grace: Duration::from_secs(DEFAULT_RECONNECT_GRACE_SECS),
+            interest_radius: interest::DEFAULT_INTEREST_RADIUS,
+            tick_rate: tick::DEFAULT_TICK_RATE,
+            rate_limits: RateLimits::default(),
+            record: None,
+            netsim: SharedSim::default(),
+        }
+    }
+}
+
+//Check a registering client speaks the same protocol, returns the reason if not
+fn check_version(protocol_version: u16) -> Result<(), String> {
+    if protocol_version != net_common::PROTOCOL_VERSION {
+        return Err(format!(
+            "Protocol version mismatch: client has {}, server has {} (build {})",
+            protocol_version,
+            net_common::PROTOCOL_VERSION,
+            net_common::BUILD_ID
+        ));
+    }
+    Ok(())
+}
+
+//Send position traffic over UDP if the client has bound it, otherwise fall back to TCP
+fn send_unreliable<N: Network<Signal>>(
+    sim: &mut NetSim<Signal, N>,
+    endpoint: Endpoint,
+    client: &mut PlayerConnection,
+    channel: &mut Channel,
+    recorder: &mut Option<Recorder>,
+    command: &Commands,
+) {
+    if let Some(recorder) = recorder {
+        recorder.record(endpoint, false, command);
+    }
+    match client.udp {
+        Some(udp) => {
+            let tosend = channel.seal_datagram(&client.udp_seq.wrap(command));
+            sim.send(udp, &tosend);
+        }
+        None => {
+            let tosend = channel.seal(&bincode::serialize(command).unwrap());
+            sim.send(endpoint, &tosend);
+        }
+    }
+}
+
+//Everything the host keeps track of while running, on whichever network N is
+struct Host<N> {
+    net: N,
+    state_lock: Arc<Mutex<game::GameState>>,
+    settings: ServerSettings,
+    clients: HashMap<Endpoint, Connection>,
+    udp_listeners: Vec<ResourceId>, //Two where IPv4 is listened for separately from IPv6
+    udp_clients: HashMap<Endpoint, Endpoint>, //UDP endpoint to TCP endpoint
+    channels: HashMap<Endpoint, Channel>, //Encryption for each client that has done the handshake, by TCP endpoint
+    malformed: HashMap<Endpoint, u32>, //Messages that couldn't be decoded or had bad fields, by TCP endpoint
+    limiters: HashMap<Endpoint, RateLimiter>, //By TCP endpoint, from the handshake on
+    bans: Bans,
+    queries: SourceLimiter, //Status and discovery queries, by address
+    unauthenticated: SourceLimiter, //Datagrams not yet known to be from a client, by address
+    challenges: Challenges, //For queries, so replies only go where they were asked for
+    key: StaticKey,
+    discovery: Option<ResourceId>,
+    port: u16, //Game port, for discovery replies
+    suspended: HashMap<SessionDigest, Suspended>, //By digest of the session token, all a migrated host is given
+    ids: IdPool,
+    clock: TickClock,
+    start: Instant, //Snapshots are stamped relative to this
+    recorder: Option<Recorder>,
+    sim: NetSim<Signal, N>, //Everything goes through this, straight through unless network conditions are being simulated
+}
+
+impl<N: Network<Signal>> Host<N> {
+    //Whether to give a full reply to a query, if not it's either dropped or answered with a challenge
+    fn query_allowed(&mut self, endpoint: Endpoint, challenge: u64, make_challenge: fn(u64) -> Vec<u8>) -> bool {
+        let ip = endpoint.addr().ip();
+        if !self.queries.allow(ip) {
+            return false;
+        }
+        if self.challenges.check(ip, challenge) {
+            return true;
+        }
+        self.sim.send(endpoint, &make_challenge(self.challenges.issue(ip)));
+        false
+    }
+
+    //Report what the server is running to someone who hasn't joined
+    fn answer_status(&mut self, endpoint: Endpoint, data: &[u8]) {
+        let Some(challenge) = status::query_challenge(data) else {
+            return;
+        };
+        if !self.query_allowed(endpoint, challenge, status::challenge) {
+            return;
+        }
+        let status = {
+            let state = self.state_lock.lock().unwrap();
+            let mut players: Vec<StatusPlayer> = state
+                .players
+                .iter()
+                .map(|(id, p)| StatusPlayer { id: *id, name: p.name.clone() })
+                .collect();
+            players.sort_by_key(|p| p.id);
+            let mut spectators: Vec<String> = self
+                .clients
+                .values()
+                .filter_map(|c| c.player().and_then(|p| p.spectator.clone()))
+                .collect();
+            spectators.sort();
+            ServerStatus {
+                protocol_version: net_common::PROTOCOL_VERSION,
+                build_id: net_common::BUILD_ID.to_string(),
+                name: self.settings.name.clone(),
+                map: state.map_name.clone(),
+                players,
+                spectators,
+                max_players: self.settings.max_players,
+                uptime_secs: clock::since(self.start).as_secs(),
+                password: self.settings.password.is_some(),
+            }
+        };
+        self.sim.send(endpoint, &status::reply(status));
+    }
+
+    //Tell a client looking for LAN games about this one
+    fn answer_discovery(&mut self, endpoint: Endpoint, data: &[u8]) {
+        let Some(challenge) = discovery::query_challenge(data) else {
+            return;
+        };
+        if !self.query_allowed(endpoint, challenge, discovery::challenge) {
+            return;
+        }
+        let info = {
+            let state = self.state_lock.lock().unwrap();
+            ServerInfo {
+                protocol_version: net_common::PROTOCOL_VERSION,
+                name: self.settings.name.clone(),
+                map: state.map_name.clone(),
+                players: state.players.len() as u8,
+                max_players: self.settings.max_players,
+                port: self.port,
+                password: self.settings.password.is_some(),
+            }
+        };
+        self.sim.send(endpoint, &discovery::reply(info));
+    }
+
+    //Send over a client's encrypted TCP connection, nothing is sent before the handshake
+    fn send(&mut self, endpoint: Endpoint, command: &Commands) {
+        let Some(channel) = self.channels.get_mut(&endpoint) else {
+            return;
+        };
+        let tosend = channel.seal(&bincode::serialize(command).unwrap());
+        self.sim.send(endpoint, &tosend);
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(endpoint, false, command);
+        }
+    }
+
+    //Send to every client that has the player list, i.e registered or in world
+    fn broadcast_registered(&mut self, except: Option<Endpoint>, command: &Commands) {
+        let tosend = bincode::serialize(command).unwrap();
+        for (c, connection) in &self.clients {
+            if connection.player().is_none() || Some(*c) == except {
+                continue;
+            }
+            if let Some(channel) = self.channels.get_mut(c) {
+                self.sim.send(*c, &channel.seal(&tosend));
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.record(*c, false, command);
+                }
+            }
+        }
+    }
+
+    //Pass a message to whatever handles what it was sent to
+    fn receive(&mut self, endpoint: Endpoint, data: &[u8]) {
+        if self.udp_listeners.contains(&endpoint.resource_id()) {
+            self.handle_datagram(endpoint, data);
+        } else if Some(endpoint.resource_id()) == self.discovery {
+            self.answer_discovery(endpoint, data);
+        } else {
+            self.handle_message(endpoint, data);
+        }
+    }
+
+    fn handle_message(&mut self, endpoint: Endpoint, data: &[u8]) {
+        match self.clients.get(&endpoint) {
+            //Left over from a client that's been dropped, e.g for flooding
+            None => return,
+            //First message is the handshake, everything after is encrypted
+            Some(Connection::Handshaking(_)) => {
+                self.handshake(endpoint, data);
+                return;
+            }
+            Some(_) => (),
+        }
+
+        let Some(data) = self.channels.get_mut(&endpoint).and_then(|c| c.open(data)) else {
+            //Corrupted or tampered with, nothing after it can be trusted either
+            println!("Could not decrypt message from {}, dropping", endpoint);
+            self.drop_client(endpoint);
+            return;
+        };
+        //Still decrypted when over the limit, or the nonces would get out of step
+        if !self.within_limits(endpoint, None) {
+            return;
+        }
+        let Some(connection) = self.clients.get_mut(&endpoint) else {
+            return;
+        };
+        if let Some(client) = connection.player_mut() {
+            client.heartbeat.heard();
+        }
+
+        let command = match wire::decode_command(&data) {
+            Ok(command) => command,
+            Err(err) => {
+                //Likely a client from another version, tell it instead of ignoring it
+                if let Some(version) = net_common::peek_protocol_version(&data) {
+                    if let Err(reason) = check_version(version) {
+                        self.reject_client(endpoint, reason);
+                        return;
                     }
-                    Err(err) => {
-                        println!("{}", err);
+                }
+                //Registration can be told what was wrong with it
+                if let Connection::Accepted(..) = connection {
+                    self.reject_client(endpoint, format!("Bad registration: {}", err));
+                    return;
+                }
+                self.malformed(endpoint, &err);
+                return;
+            }
+        };
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(endpoint, true, &command);
+        }
+
+        if !self.within_limits(endpoint, Some(command.name())) {
+            return;
+        }
+        let Some(connection) = self.clients.get_mut(&endpoint) else {
+            return;
+        };
+
+        //Only handle what makes sense for where the client is in joining, anything else is dropped
+        match (connection, command) {
+            (Connection::Accepted(_, challenge), Commands::RegisterPlayer(player_info)) => {
+                let challenge = *challenge;
+                self.register(endpoint, challenge, player_info);
+            }
+            (Connection::Accepted(..), command) => {
+                //Can't be a working client, so don't let it hang around
+                let reason = format!("Sent {} before registering", command.name());
+                self.reject_client(endpoint, reason);
+            }
+            (Connection::Registered(_), Commands::ClientReady) => self.enter_world(endpoint),
+            (Connection::InWorld(client), Commands::Inputs(inputs)) if client.spectator.is_none() => {
+                self.handle_inputs(endpoint, inputs)
+            }
+            (Connection::InWorld(client), Commands::AckSnapshot(seq)) => client.snapshots.ack(seq),
+            (Connection::Registered(_) | Connection::InWorld(_), Commands::Ping(time)) => {
+                self.send(endpoint, &Commands::Pong(time));
+            }
+            (Connection::Registered(client) | Connection::InWorld(client), Commands::Pong(time)) => {
+                client.heartbeat.pong(time);
+            }
+            (connection, command) => {
+                println!("Ignoring {} from {} while {}", command.name(), endpoint, connection.stage_name());
+            }
+        }
+    }
+
+    fn handle_datagram(&mut self, endpoint: Endpoint, data: &[u8]) {
+        //Status queries come from anyone, not just players
+        if status::is_status(data) {
+            self.answer_status(endpoint, data);
+            return;
+        }
+
+        //Client datagrams start with their UDP token, to find the session whose keys decrypt the rest
+        if data.len() < 8 {
+            return;
+        }
+        let (token, sealed) = data.split_at(8);
+        let token = u64::from_le_bytes(token.try_into().unwrap());
+        //Until it decrypts a datagram could be from anyone, the token and address are easily copied, so it's only
+        //counted against its address and never against the client it claims to be from
+        let ip = endpoint.addr().ip().to_canonical();
+        let bound = self.udp_clients.get(&endpoint).copied();
+        let tcp = match bound {
+            Some(tcp) => tcp,
+            None => {
+                //Not from an address a client has bound, only worth the work of decrypting so often
+                if !self.unauthenticated.allow(ip) {
+                    return;
+                }
+                let found = self
+                    .clients
+                    .iter()
+                    .find(|(_, c)| c.player().is_some_and(|p| p.udp_token == token))
+                    .map(|(tcp, _)| *tcp);
+                let Some(tcp) = found else {
+                    println!("Unknown UDP token from {}", endpoint);
+                    return;
+                };
+                tcp
+            }
+        };
+        let Some(data) = self.channels.get(&tcp).and_then(|c| c.open_datagram(sealed)) else {
+            //Only logged within the limit, so forgeries can't flood the log
+            if bound.is_none() || self.unauthenticated.allow(ip) {
+                println!("Could not decrypt datagram from {}", endpoint);
+            }
+            return;
+        };
+
+        let datagram = match wire::decode_datagram(&data) {
+            Ok(datagram) => datagram,
+            Err(err) => {
+                //It decrypted, so it really came from this client
+                self.malformed(tcp, &err);
+                return;
+            }
+        };
+        //A copy of one already handled could have been captured and sent again by anyone, from any address, it's not
+        //the client's doing
+        let replayed = self
+            .clients
+            .get(&tcp)
+            .and_then(Connection::player)
+            .is_some_and(|c| !c.udp_seq.is_new(datagram.seq));
+        if replayed || !self.within_limits(tcp, None) {
+            return;
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(tcp, true, &datagram.command);
+        }
+        if !self.within_limits(tcp, Some(datagram.command.name())) {
+            return;
+        }
+        let Some(client) = self.clients.get_mut(&tcp).and_then(Connection::player_mut) else {
+            return;
+        };
+
+        match datagram.command {
+            Commands::BindUdp(_) => {
+                //Decrypting it proved it came from the client with this session, so tie the address to it
+                if client.udp != Some(endpoint) {
+                    if let Some(old) = client.udp {
+                        self.udp_clients.remove(&old);
+                    }
+                    println!("Player {} bound UDP {}", client.id, endpoint.addr());
+                    //Sequence numbers carry on across addresses, so nothing from before can be replayed from here
+                    client.udp = Some(endpoint);
+                    self.udp_clients.insert(endpoint, tcp);
+                }
+                client.udp_seq.accept(datagram.seq);
+
+                if let Some(channel) = self.channels.get_mut(&tcp) {
+                    let tosend = channel.seal_datagram(&client.udp_seq.wrap(&Commands::UdpBound));
+                    self.sim.send(endpoint, &tosend);
+                    if let Some(recorder) = &mut self.recorder {
+                        recorder.record(tcp, false, &Commands::UdpBound);
                     }
                 }
             }
-            NetEvent::Disconnected(_endpoint) => {
-                //TODO: Notify other clients
-                let p = clients.get(&_endpoint).unwrap();
-                let mut game = state_lock.lock().unwrap();
-                game.players.remove(&p); //Remove player from game
-                drop(game);
-                let tosend = bincode::serialize(&Commands::RemovePlayer(*p)).unwrap();
-                clients.remove(&_endpoint); //Remove client from list of endpoints
-
-                for (c, _) in &clients {
-                    handler.network().send(*c, &tosend);   
-                }
-                println!("Client disconnected");
-            } //Tcp or Ws
-        },
-        NodeEvent::Signal(signal) => match signal  {
-            Signal::UpdateClients =>  {
-                //Try and update clients
-                let mut new_positions: Vec<PositionMap> = Vec::new();
-
-                let game = state_lock.lock().unwrap();
-                for (id, player) in &game.players {
-                    if !positions.contains_key(id)
-                        || !player.position.equals(positions.get(&id).unwrap())
-                    {
-                        positions.insert(*id, player.position); //Update position
-                        new_positions.push(PositionMap {
-                            id: *id,
-                            pos: player.position,
-                        }); //Add to list to send to clients
+            command => {
+                if client.udp != Some(endpoint) {
+                    return; //Not bound, ignore
+                }
+
+                //Drop anything older than what's already arrived
+                if !client.udp_seq.accept(datagram.seq) {
+                    return;
+                }
+                client.heartbeat.heard();
+
+                match (self.clients.get_mut(&tcp), command) {
+                    (Some(Connection::InWorld(client)), Commands::Inputs(inputs)) if client.spectator.is_none() => {
+                        self.handle_inputs(tcp, inputs)
                     }
+                    (Some(Connection::InWorld(client)), Commands::AckSnapshot(seq)) => client.snapshots.ack(seq),
+                    (_, command) => println!("Ignoring {} datagram from {}", command.name(), endpoint),
                 }
+            }
+        }
+    }
+
+    //Answer a new client's handshake, then give it a challenge to answer when it registers
+    fn handshake(&mut self, endpoint: Endpoint, request: &[u8]) {
+        let Some((channel, reply)) = secure::accept(&self.key, request) else {
+            println!("Bad handshake from {}", endpoint);
+            //Clients from before encryption register straight away, and can still read a plain rejection
+            if let Some(version) = net_common::peek_protocol_version(request) {
+                if let Err(reason) = check_version(version) {
+                    let tosend = bincode::serialize(&Commands::RejectRegistration(reason)).unwrap();
+                    self.sim.send(endpoint, &tosend);
+                }
+            }
+            self.drop_client(endpoint);
+            return;
+        };
+        self.sim.send(endpoint, &reply);
+        self.channels.insert(endpoint, channel);
+        self.limiters.insert(endpoint, RateLimiter::new(&self.settings.rate_limits));
+
+        let challenge = rand::random();
+        self.clients.insert(endpoint, Connection::Accepted(clock::now(), challenge));
+        self.send(endpoint, &Commands::Challenge(challenge));
+    }
+
+    //Give a new client an id, a player and the world, or tell it why not
+    fn register(&mut self, endpoint: Endpoint, challenge: u64, player_info: RegistrationInfo) {
+        println!("Attempting to register");
+        if let Err(reason) = check_version(player_info.protocol_version) {
+            self.reject_client(endpoint, reason);
+            return;
+        }
+        if let Some(password) = &self.settings.password {
+            let reason = match &player_info.password {
+                None => Some("This server needs a password"),
+                Some(proof) if !auth::check(password, challenge, proof) => Some("Incorrect password"),
+                Some(_) => None,
+            };
+            if let Some(reason) = reason {
+                self.reject_client(endpoint, reason.to_string());
+                return;
+            }
+        }
+        if player_info.build_id != net_common::BUILD_ID {
+            println!("Client build {} differs from server build {}", player_info.build_id, net_common::BUILD_ID);
+        }
+        if player_info.spectator {
+            self.register_spectator(endpoint, player_info.name);
+            return;
+        }
+
+        //Take back the old player if the client still has a valid session, otherwise start a new one
+        let mut resumed = None;
+        if let Some(session) = player_info.session {
+            resumed = self.take_session(session).map(|id| (id, session));
+        }
+        let (id, session) = match resumed {
+            Some(resumed) => resumed,
+            None => {
+                let Some(id) = self.ids.allocate() else {
+                    let reason = format!("Server is full (limit is {} players)", self.settings.max_players);
+                    self.reject_client(endpoint, reason);
+                    return;
+                };
+                (id, rand::random())
+            }
+        };
+        let connection = PlayerConnection::new(id, session, self.settings.interest_radius, player_info.host_key);
+        let token = connection.udp_token;
+        self.clients.insert(endpoint, Connection::Registered(connection));
+
+        let mut state = self.state_lock.lock().unwrap();
+        //Returning players keep their name, colour and position
+        let mut new_player = None;
+        if resumed.is_some() {
+            state.reconnecting.remove(&id);
+        } else {
+            let player = NetPlayer {
+                colour: player_info.colour,
+                id,
+                name: player_info.name,
+                position: state.spawn,
+            };
+            state.players.insert(id, player.clone());
+            new_player = Some(player);
+        }
+
+        //Send info to new client
+        let mut players = Vec::new();
+        for p in state.players.values() {
+            players.push(p.clone());
+        }
+        let map = Commands::SendMap(Map { buildings: state.buildings.clone() });
+        let player_info = Commands::SendPlayerInfo(net_common::NetPlayerInfo {
+            players,
+            your_num: id,
+            reconnecting: state.reconnecting.iter().copied().collect(),
+        });
+        //Copied out, now release lock
+
+        drop(state);
+
+        //Send map and players
+        self.send(endpoint, &map);
+        self.send(endpoint, &player_info);
+
+        //Update other clients
+        match new_player {
+            Some(player) => self.broadcast_registered(Some(endpoint), &Commands::AddPlayer(player)),
+            None => self.broadcast_registered(Some(endpoint), &Commands::Reconnected(id)),
+        }
+
+        //Token for getting this player back if the connection drops
+        self.send(endpoint, &Commands::Session(session));
+
+        //Client ready
+        self.send(endpoint, &Commands::AllowClientReady(id));
+
+        //Let the client move position traffic to UDP
+        if !self.udp_listeners.is_empty() {
+            self.send(endpoint, &Commands::BindUdp(token));
+        }
+    }
+
+    //Send a spectator the world without giving it a player, nobody else is told it's there
+    fn register_spectator(&mut self, endpoint: Endpoint, name: String) {
+        let spectators = self.clients.values().filter(|c| c.player().is_some_and(|p| p.spectator.is_some())).count();
+        if spectators >= self.settings.max_spectators as usize {
+            let reason = format!("No room for spectators (limit is {})", self.settings.max_spectators);
+            self.reject_client(endpoint, reason);
+            return;
+        }
+        println!("{} is spectating", name);
+        let connection = PlayerConnection::spectator(name);
+        let token = connection.udp_token;
+        self.clients.insert(endpoint, Connection::Registered(connection));
+
+        let state = self.state_lock.lock().unwrap();
+        let map = Commands::SendMap(Map { buildings: state.buildings.clone() });
+        let player_info = Commands::SendPlayerInfo(net_common::NetPlayerInfo {
+            players: state.players.values().cloned().collect(),
+            your_num: net_common::SPECTATOR_ID,
+            reconnecting: state.reconnecting.iter().copied().collect(),
+        });
+        drop(state);
+
+        //No session, there's no player to take back so a reconnect just spectates again
+        self.send(endpoint, &map);
+        self.send(endpoint, &player_info);
+        self.send(endpoint, &Commands::AllowClientReady(net_common::SPECTATOR_ID));
+        if !self.udp_listeners.is_empty() {
+            self.send(endpoint, &Commands::BindUdp(token));
+        }
+    }
+
+    //Find the player a session belongs to and detach it from any old connection
+    fn take_session(&mut self, session: u64) -> Option<u8> {
+        if let Some(suspended) = self.suspended.remove(&auth::session_digest(session)) {
+            println!("Player {} reconnected", suspended.id);
+            return Some(suspended.id);
+        }
+
+        //Old connection may not have timed out yet, e.g if it was half-open
+        let old = self
+            .clients
+            .iter()
+            .find(|(_, c)| c.player().is_some_and(|p| p.session == session))
+            .map(|(endpoint, _)| *endpoint)?;
+        let (id, udp) = self.clients.remove(&old)?.player().map(|p| (p.id, p.udp))?;
+        self.channels.remove(&old);
+        self.malformed.remove(&old);
+        self.limiters.remove(&old);
+        self.net.remove(old.resource_id());
+        if let Some(udp) = udp {
+            self.udp_clients.remove(&udp);
+        }
+        println!("Player {} reconnected, replacing old connection", id);
+        Some(id)
+    }
+
+    //Client has loaded the world, start sending it updates
+    fn enter_world(&mut self, endpoint: Endpoint) {
+        if let Some(Connection::Registered(player)) = self.clients.remove(&endpoint) {
+            if player.spectator.is_none() {
+                println!("Player {} is in the world", player.id);
+            }
+            self.clients.insert(endpoint, Connection::InWorld(player));
+            self.announce_successor();
+        }
+    }
+
+    //Tell everyone who takes over if the hosting player leaves, the lowest id in the world that's able to
+    //Only player hosts leave with the game, so there's no successor for a dedicated server
+    fn announce_successor(&mut self) {
+        if !self.settings.host_player {
+            return;
+        }
+        let host = self.state_lock.lock().unwrap().own_player;
+        let successor = self
+            .clients
+            .iter()
+            .filter_map(|(endpoint, connection)| match connection {
+                Connection::InWorld(client) => client.host_key.map(|key| (*endpoint, client.id, key)),
+                _ => None,
+            })
+            .min_by_key(|(_, id, _)| *id)
+            .map(|(endpoint, id, key)| {
+                let successor = Successor {
+                    id,
+                    host,
+                    address: SocketAddr::new(endpoint.addr().ip().to_canonical(), self.port),
+                    key,
+                    sessions: Vec::new(),
+                    max_players: self.settings.max_players,
+                    rate_limits: self.settings.rate_limits,
+                    password: None,
+                };
+                (endpoint, successor)
+            });
+
+        //The successor is sent everyone, so it has the whole game when it takes over
+        let radius = self.settings.interest_radius;
+        for (other, connection) in &mut self.clients {
+            if let Some(client) = connection.player_mut().filter(|c| c.spectator.is_none()) {
+                let next = successor.as_ref().is_some_and(|(endpoint, _)| endpoint == other);
+                client.interest.set_radius(if next { f32::INFINITY } else { radius });
+            }
+        }
 
-                // if !positions.contains_key(&0)
-                //     || game.own_player.position != *positions.get(&0).unwrap()
-                // {
-                //     //Add own player
-                //     positions.insert(0, game.own_player.position);
-                //     new_positions.push(PositionMap {
-                //         id: 0,
-                //         pos: NetPosition::from_vec2(game.own_player.position),
-                //     });
-                // }
-
-                drop(game); //Release lock before send
-
-                let tosend = bincode::serialize(&Commands::MovedPlayers(new_positions)).unwrap();
-                // let mut handles = Vec::new();
-
-                if !tosend.is_empty() {
-                    for (i, _) in &clients { //Will this work?
-                        // let _status = handler.network().send(*i, &tosend);
-                        //  handles.push(async {
-                        // }) ;
-                        handler.network().send(*i, &tosend);
+        let Some((endpoint, mut successor)) = successor else {
+            self.broadcast_registered(None, &Commands::Successor(None));
+            return;
+        };
+        self.broadcast_registered(Some(endpoint), &Commands::Successor(Some(successor.clone())));
+
+        //Only the successor needs to know who can take their player back, and only by digest, or the password
+        let connected = self
+            .clients
+            .values()
+            .filter_map(Connection::player)
+            .filter(|c| c.spectator.is_none())
+            .map(|c| (auth::session_digest(c.session), c.id));
+        let suspended = self.suspended.iter().map(|(digest, s)| (*digest, s.id));
+        successor.sessions = connected.chain(suspended).filter(|(_, id)| *id != successor.id).collect();
+        successor.password = self.settings.password.clone();
+        self.send(endpoint, &Commands::Successor(Some(successor)));
+    }
+
+    //Queue inputs from a client for the next tick, skipping anything already applied or queued
+    fn handle_inputs(&mut self, endpoint: Endpoint, inputs: Vec<MoveInput>) {
+        let Some(client) = self.clients.get_mut(&endpoint).and_then(Connection::player_mut) else {
+            return;
+        };
+
+        let mut newest = client.queued.last().map_or(client.last_input, |i| i.seq);
+        let mut queued = false;
+        for input in &inputs {
+            if input.seq > newest {
+                newest = input.seq;
+                client.queued.push(*input);
+                queued = true;
+            }
+        }
+
+        //Only resends, so the last ack was probably lost, send it again on the next update
+        if !queued && !inputs.is_empty() {
+            client.acked_input = 0;
+        }
+    }
+
+    //Simulate queued inputs, correcting clients straight away if any were illegal
+    fn simulate(&mut self) {
+        let mut corrections = Vec::new();
+
+        let mut state = self.state_lock.lock().unwrap();
+        let game::GameState { players, buildings, .. } = &mut *state;
+        for (endpoint, connection) in self.clients.iter_mut() {
+            let Some(client) = connection.player_mut() else {
+                continue;
+            };
+            if client.queued.is_empty() {
+                continue;
+            }
+            let Some(player) = players.get_mut(&client.id) else {
+                println!("Trying to move non-existent player with unlinked endpoint!");
+                client.queued.clear();
+                continue;
+            };
+
+            client.validator.refill();
+            let mut corrected = false;
+
+            //Simulate with the same rules as the client
+            for input in client.queued.drain(..) {
+                let (input, violation) = client.validator.check(&input);
+                if let Some(reason) = violation {
+                    println!(
+                        "Movement violation by player {} ({}): {}, {} so far",
+                        client.id, player.name, reason, client.validator.violations
+                    );
+                    corrected = true;
+                }
+
+                player.position = movement::apply_input(buildings, player.position, &input);
+                client.last_input = input.seq;
+            }
+
+            //Correct the client straight away instead of on the next update
+            if corrected {
+                client.acked_input = client.last_input;
+                corrections.push((*endpoint, InputAck { seq: client.last_input, pos: player.position }));
+            }
+        }
+        drop(state);
+
+        for (endpoint, ack) in corrections {
+            let client = self.clients.get_mut(&endpoint).and_then(Connection::player_mut);
+            if let (Some(client), Some(channel)) = (client, self.channels.get_mut(&endpoint)) {
+                send_unreliable(&mut self.sim, endpoint, client, channel, &mut self.recorder, &Commands::AckInput(ack));
+            }
+        }
+    }
+
+    //Run one tick of the simulation and send the results, then schedule the next
+    fn tick(&mut self) {
+        //Stop here instead of from the other thread, so clients aren't left mid-update
+        if self.settings.shutdown.load(Ordering::Relaxed) {
+            println!("Shutting down");
+            //Game carries on with the successor
+            if self.settings.host_player {
+                self.broadcast_registered(None, &Commands::HostLeaving);
+            }
+            self.net.stop();
+            return;
+        }
+
+        let tick = self.clock.begin();
+        self.simulate();
+        self.update_clients(tick);
+
+        let wait = self.clock.end();
+        if let Some(stats) = self.clock.report() {
+            if stats.late > 0 || stats.overruns > 0 {
+                println!(
+                    "{} ticks: {} late (worst {:.1} ms), {} skipped, {} overran (longest took {:.1} ms)",
+                    stats.ticks,
+                    stats.late,
+                    stats.max_late.as_secs_f32() * 1000.0,
+                    stats.skipped,
+                    stats.overruns,
+                    stats.max_work.as_secs_f32() * 1000.0
+                );
+            }
+        }
+        self.net.signal_after(Signal::Tick, wait);
+    }
+
+    //Count a message from a client that didn't make sense, dropping the client once it's sent too many
+    fn malformed(&mut self, endpoint: Endpoint, err: &str) {
+        let count = self.malformed.entry(endpoint).or_insert(0);
+        *count += 1;
+        println!("Malformed message from {} ({} so far): {}", endpoint, count, err);
+        if *count >= MAX_MALFORMED {
+            println!("Dropping {} for sending too many malformed messages", endpoint);
+            self.drop_client(endpoint);
+        }
+    }
+
+    //Check a client's message against its limit for everything, or for that type of message if given,
+    //returns whether to handle it
+    fn within_limits(&mut self, endpoint: Endpoint, name: Option<&'static str>) -> bool {
+        let Some(limiter) = self.limiters.get_mut(&endpoint) else {
+            return true;
+        };
+        let limits = &self.settings.rate_limits;
+        let verdict = match name {
+            Some(name) => limiter.check_type(limits, name),
+            None => limiter.check_total(limits),
+        };
+        let what = name.unwrap_or("messages");
+        match verdict {
+            Verdict::Allow => true,
+            Verdict::Drop => false,
+            Verdict::Warn => {
+                println!("{} is sending {} too fast, dropping them", endpoint, what);
+                let warning = format!("Sending {} too fast, they're being dropped and you'll be kicked if it carries on", what);
+                self.send(endpoint, &Commands::Warning(warning));
+                false
+            }
+            Verdict::Kick => {
+                self.kick(endpoint, format!("Sending {} too fast", what));
+                false
+            }
+        }
+    }
+
+    //Drop a client for misbehaving, its player goes too as it isn't coming back, and ban its address if it's been kicked too often
+    fn kick(&mut self, endpoint: Endpoint, reason: String) {
+        println!("Kicking {}: {}", endpoint, reason);
+        self.send(endpoint, &Commands::Kick(reason));
+        let session = self.clients.get(&endpoint).and_then(Connection::player).map(|c| c.session);
+        self.drop_client(endpoint);
+        if let Some(suspended) = session.and_then(|s| self.suspended.remove(&auth::session_digest(s))) {
+            self.remove_player(suspended.id);
+        }
+
+        let ip = endpoint.addr().ip().to_canonical();
+        if self.bans.kicked(ip, &self.settings.rate_limits) {
+            println!("Banning {} for {}s", ip, self.settings.rate_limits.ban_time.as_secs());
+        }
+    }
+
+    //Tell a client why it can't join, then drop its connection
+    fn reject_client(&mut self, endpoint: Endpoint, reason: String) {
+        println!("Rejecting {}: {}", endpoint, reason);
+        self.send(endpoint, &Commands::RejectRegistration(reason));
+        self.drop_client(endpoint);
+    }
+
+    //Close a client's connection from this side
+    fn drop_client(&mut self, endpoint: Endpoint) {
+        //Anything said last, e.g why it was kicked, would otherwise go with the connection
+        self.sim.flush(endpoint);
+        self.net.remove(endpoint.resource_id());
+        //Removing doesn't generate a disconnect event, so clean up here
+        self.remove_client(endpoint);
+    }
+
+    //Ping every client, and drop any that have gone quiet or never registered
+    fn heartbeat(&mut self) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.flush();
+        }
+        let timeout = self.settings.timeout;
+        let mut timed_out = Vec::new();
+        let mut pings = Vec::new();
+
+        for (endpoint, connection) in self.clients.iter() {
+            match connection {
+                Connection::Handshaking(since) | Connection::Accepted(since, _) => {
+                    if clock::since(*since) > timeout {
+                        println!("{} never registered, dropping", endpoint);
+                        timed_out.push(*endpoint);
+                    }
+                }
+                Connection::Registered(client) | Connection::InWorld(client) => {
+                    if client.heartbeat.silent_for() > timeout {
+                        println!("Player {} timed out after {:.1}s", client.id, client.heartbeat.silent_for().as_secs_f32());
+                        timed_out.push(*endpoint);
+                        continue;
+                    }
+
+                    pings.push((*endpoint, client.heartbeat.ping_time()));
+                }
+            }
+        }
+
+        for (endpoint, time) in pings {
+            self.send(endpoint, &Commands::Ping(time));
+        }
+
+        for endpoint in timed_out {
+            self.drop_client(endpoint);
+        }
+
+        //Give up on players that haven't come back in time
+        let grace = self.settings.reconnect_grace;
+        let expired: Vec<SessionDigest> = self
+            .suspended
+            .iter()
+            .filter(|(_, s)| clock::since(s.since) > grace)
+            .map(|(session, _)| *session)
+            .collect();
+        for session in expired {
+            if let Some(suspended) = self.suspended.remove(&session) {
+                println!("Player {} did not reconnect in time", suspended.id);
+                self.remove_player(suspended.id);
+            }
+        }
+
+        self.net.signal_after(Signal::Heartbeat, heartbeat::PING_INTERVAL);
+    }
+
+    //Forget a client and take its player out of the game
+    fn remove_client(&mut self, endpoint: Endpoint) {
+        self.channels.remove(&endpoint);
+        self.malformed.remove(&endpoint);
+        self.limiters.remove(&endpoint);
+        let Some(connection) = self.clients.remove(&endpoint) else {
+            return;
+        };
+        //Nothing else to clean up if it never registered
+        let Some(client) = connection.player() else {
+            println!("Unregistered client disconnected");
+            return;
+        };
+        if let Some(udp) = client.udp {
+            self.udp_clients.remove(&udp);
+        }
+        //No player to hold on to
+        if let Some(name) = &client.spectator {
+            println!("{} stopped spectating", name);
+            return;
+        }
+        if let Some(link) = client.heartbeat.stats() {
+            println!("Player {} last RTT {:.0} ms, jitter {:.0} ms", client.id, link.rtt_ms, link.jitter_ms);
+        }
+
+        //Keep the player around for a while so the client can reconnect
+        let p = client.id;
+        if self.settings.reconnect_grace.is_zero() {
+            self.remove_player(p);
+            return;
+        }
+        println!("Player {} disconnected, holding their slot for {}s", p, self.settings.reconnect_grace.as_secs());
+        self.suspended.insert(auth::session_digest(client.session), Suspended { id: p, since: clock::now() });
+        let mut game = self.state_lock.lock().unwrap();
+        game.reconnecting.insert(p);
+        drop(game);
+        self.broadcast_registered(None, &Commands::Reconnecting(p));
+        self.announce_successor();
+    }
+
+    //Take a player out of the game for good
+    fn remove_player(&mut self, p: u8) {
+        let mut game = self.state_lock.lock().unwrap();
+        game.players.remove(&p); //Remove player from game
+        game.reconnecting.remove(&p);
+        drop(game);
+        self.ids.release(p);
+
+        self.broadcast_registered(None, &Commands::RemovePlayer(p));
+        println!("Player {} removed", p);
+        self.announce_successor();
+    }
+
+    //Everything that happens to the host, from whichever network it's running on
+    fn handle(&mut self, event: NodeEvent<Signal>) {
+        match event {
+            NodeEvent::Network(net_event) => match net_event {
+                NetEvent::Connected(_, _) => unreachable!(), // Used for explicit connections.
+                NetEvent::Accepted(endpoint, _listener) => {
+                    //Banned addresses go before the handshake, so they can't make the server do its key exchange
+                    if let Some(left) = self.bans.banned_for(endpoint.addr().ip().to_canonical()) {
+                        println!("Turning away {}, banned", endpoint);
+                        //Not encrypted, there are no keys yet, clients read it when the handshake doesn't work
+                        let reason = format!("Banned for flooding, {}s left", left.as_secs() + 1);
+                        self.sim.send(endpoint, &bincode::serialize(&Commands::RejectRegistration(reason)).unwrap());
+                        self.sim.flush(endpoint);
+                        self.net.remove(endpoint.resource_id());
+                        return;
+                    }
+                    println!("Client connected"); //Id is given out once it registers
+                    self.clients.insert(endpoint, Connection::Handshaking(clock::now()));
+                }
+                NetEvent::Message(endpoint, data) => {
+                    if !self.sim.receive(endpoint, data) {
+                        self.receive(endpoint, data);
+                    }
+                }
+                NetEvent::Disconnected(endpoint) => self.remove_client(endpoint), //Tcp or Ws
+            },
+            NodeEvent::Signal(signal) => match signal {
+                Signal::Tick => self.tick(),
+                Signal::Heartbeat => self.heartbeat(),
+                Signal::NetSim => {
+                    for (endpoint, data) in self.sim.poll() {
+                        self.sim.handled();
+                        self.receive(endpoint, &data);
                     }
                 }
-                // for i in handles {
-                //     // i.await;
-                // }
+            },
+        }
+    }
+
+    //Send positions and input acks to everyone in the world
+    fn update_clients(&mut self, tick: u32) {
+        //Try and update clients
+        let time = clock::since(self.start).as_millis() as u64;
+
+        let game = self.state_lock.lock().unwrap();
+
+        //Pick out what each client should hear about, based on where its own player is
+        let mut announcements = Vec::new();
+        let mut snapshots = Vec::new();
+        for (endpoint, connection) in self.clients.iter_mut() {
+            let Connection::InWorld(client) = connection else {
+                continue;
+            };
+            //Spectators are sent everyone, so where they're centred doesn't matter
+            let centre = match game.players.get(&client.id) {
+                Some(own) => own.position,
+                None if client.spectator.is_some() => game.spawn,
+                None => continue,
+            };
+
+            let (entered, left) = client.interest.update(client.id, centre, &game.players);
+            for id in entered {
+                if let Some(p) = game.players.get(&id) {
+                    announcements.push((*endpoint, Commands::EnterInterest(PositionMap { id, pos: p.position })));
+                }
+            }
+            for id in left {
+                announcements.push((*endpoint, Commands::LeaveInterest(id)));
+            }
+
+            //Everything the client should know, encoded against what it already has
+            let mut state = SnapshotState::new();
+            for id in client.interest.known() {
+                let Some(p) = game.players.get(id) else {
+                    continue;
+                };
+                let mut pos = snapshot::quantise(p.position);
+                //Far players stay where they were last sent until they're due an update
+                if !client.interest.wants_update(centre, p.position, tick) {
+                    if let Some(last) = client.snapshots.last_sent().and_then(|s| s.get(id)) {
+                        pos = *last;
+                    }
+                }
+                state.insert(*id, pos);
+            }
+            let (seq, base, data) = client.snapshots.encode(state);
+            snapshots.push((*endpoint, Snapshot { tick, time, seq, base, data }));
+        }
+
+        //Tell clients where their inputs left them, so they can correct their prediction
+        let mut acks = Vec::new();
+        for (endpoint, connection) in self.clients.iter_mut() {
+            let Connection::InWorld(client) = connection else {
+                continue;
+            };
+            if client.last_input == client.acked_input {
+                continue;
+            }
+            if let Some(player) = game.players.get(&client.id) {
+                client.acked_input = client.last_input;
+                acks.push((*endpoint, InputAck { seq: client.last_input, pos: player.position }));
+            }
+        }
+
+        drop(game); //Release lock before send
+
+        //Entering and leaving go over TCP so they can't be lost
+        for (endpoint, command) in announcements {
+            self.send(endpoint, &command);
+        }
+
+        let unreliable = acks
+            .into_iter()
+            .map(|(endpoint, ack)| (endpoint, Commands::AckInput(ack)))
+            .chain(snapshots.into_iter().map(|(endpoint, snapshot)| (endpoint, Commands::MovedPlayers(snapshot))));
+        for (endpoint, command) in unreliable {
+            let client = self.clients.get_mut(&endpoint).and_then(Connection::player_mut);
+            if let (Some(client), Some(channel)) = (client, self.channels.get_mut(&endpoint)) {
+                send_unreliable(&mut self.sim, endpoint, client, channel, &mut self.recorder, &command);
+            }
+        }
+    }
+}
+
+//Listen for TCP, and UDP on the same port, returning the address listened on and the UDP listener
+//Positions go over UDP, TCP still works for everything if that fails
+fn listen_game<N: Network<Signal>>(net: &N, address: SocketAddr) -> std::io::Result<(SocketAddr, Option<ResourceId>)> {
+    let (_, tcp_address) = net.listen(Transport::FramedTcp.into(), address)?;
+    let udp_listener = match net.listen(Transport::Udp.into(), tcp_address) {
+        Ok((id, _)) => Some(id),
+        Err(err) => {
+            println!("Could not listen for UDP on {}: {}, using TCP only", tcp_address, err);
+            None
+        }
+    };
+    Ok((tcp_address, udp_listener))
+}
+
+//Listen and get everything ready for the first tick, or report why the host can't run
+fn start_host<N: Network<Signal>>(net: N, state_lock: Arc<Mutex<game::GameState>>, mut settings: ServerSettings) -> Option<Host<N>> {
+    //Clients ack every snapshot, so high tick rates need more room than the limits may give
+    let limits = &mut settings.rate_limits;
+    let needed = settings.tick_rate as f32 * 1.5;
+    if limits.updates.rate < needed {
+        let extra = needed - limits.updates.rate;
+        limits.updates = Limit { rate: needed, burst: limits.updates.burst.max(needed) };
+        limits.total = Limit { rate: limits.total.rate + extra, burst: limits.total.burst + extra };
+        println!("Raised update rate limit to {:.0}/s for tick rate {}", needed, settings.tick_rate);
+    }
+
+    //Machines without IPv6 can still host on every IPv4 address
+    let listen_res = listen_game(&net, settings.address).or_else(|err| {
+        if settings.address != address::any_address(settings.address.port()) {
+            return Err(err);
+        }
+        let fallback = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), settings.address.port());
+        println!("Could not listen on {}: {}, trying {}", settings.address, err, fallback);
+        listen_game(&net, fallback)
+    });
+
+    let (tcp_address, udp_listener) = match listen_res {
+        Ok(listening) => listening,
+        Err(err) => {
+            println!("Could not listen on {}: {}", settings.address, err);
+            net_common::set_error(&state_lock, err.to_string());
+            return None;
+        }
+    };
+    let mut udp_listeners: Vec<ResourceId> = udp_listener.into_iter().collect();
+
+    //IPv6 sockets take IPv4 too on most systems, which leaves the port taken and this failing. Where they
+    //don't (e.g Windows) IPv4 needs its own listeners
+    if tcp_address.ip() == Ipv6Addr::UNSPECIFIED {
+        let any_v4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), tcp_address.port());
+        if let Ok((_, udp_listener)) = listen_game(&net, any_v4) {
+            println!("Listening for IPv4 separately on {}", any_v4);
+            udp_listeners.extend(udp_listener);
+        }
+    }
 
-                handler.signals().send_with_timer(Signal::UpdateClients, Duration::from_millis(15)); //Wait before next update
+    //Discovery is optional, e.g another server on this machine may already have the port
+    //Bound to every address, which also gets broadcasts
+    let discovery = if settings.discoverable {
+        let config = TransportListen::Udp(UdpListenConfig::default().with_reuse_address());
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), discovery::DISCOVERY_PORT);
+        match net.listen(config, address) {
+            Ok((id, _)) => Some(id),
+            Err(err) => {
+                println!("Could not listen for LAN discovery on {}: {}", address, err);
+                None
             }
-        },
+        }
+    } else {
+        None
+    };
+
+    //Same key every run, so clients can tell it's the same server
+    let key = match settings.key.take().map_or_else(|| StaticKey::load_or_create(&settings.key_file), Ok) {
+        Ok(key) => key,
+        Err(err) => {
+            println!(
+                "Could not load server key from {}: {}, using a temporary one that clients will see change",
+                settings.key_file.display(),
+                err
+            );
+            StaticKey::generate()
+        }
+    };
+    println!("Server key fingerprint {}", key.fingerprint());
+
+    //Own player and any carried over from another host already have their ids
+    let mut ids = IdPool::new(settings.max_players);
+    let mut suspended = HashMap::new();
+    let mut state = state_lock.lock().unwrap();
+    if settings.host_player {
+        ids.take(state.own_player);
+    }
+    for (session, id) in settings.migrated.drain(..) {
+        ids.take(id);
+        state.reconnecting.insert(id);
+        suspended.insert(session, Suspended { id, since: clock::now() });
+    }
+    drop(state);
+
+    //Recording is extra, the game runs the same without it
+    let start = clock::now();
+    let recorder = settings.record.as_ref().and_then(|path| match Recorder::create(path, start) {
+        Ok(recorder) => {
+            println!("Recording to {}", path.display());
+            Some(recorder)
+        }
+        Err(err) => {
+            println!("Could not record to {}: {}", path.display(), err);
+            None
+        }
     });
+
+    let netsim = Arc::clone(&settings.netsim);
+    let queries = SourceLimiter::new(settings.rate_limits.queries);
+    let unauthenticated = SourceLimiter::new(settings.rate_limits.unauthenticated);
+    let host = Host {
+        net: net.clone(),
+        state_lock,
+        ids,
+        clock: TickClock::new(settings.tick_rate),
+        settings,
+        clients: HashMap::new(),
+        udp_listeners,
+        udp_clients: HashMap::new(),
+        channels: HashMap::new(),
+        malformed: HashMap::new(),
+        limiters: HashMap::new(),
+        bans: Bans::default(),
+        queries,
+        unauthenticated,
+        challenges: Challenges::default(),
+        key,
+        discovery,
+        port: tcp_address.port(),
+        suspended,
+        start,
+        recorder,
+        sim: NetSim::new(net.clone(), || Signal::NetSim, netsim),
+    };
+
+    net.signal(Signal::Tick); //Start the tick loop, the clock started just now
+
+    net.signal_after(Signal::Heartbeat, heartbeat::PING_INTERVAL);
+
+    println!("Running on {}", tcp_address);
+    Some(host)
+}
+
+//Run a listener for any new connections
+pub fn run_host(state_lock: Arc<Mutex<game::GameState>>, settings: ServerSettings) {
+    let (handler, listener) = node::split::<Signal>();
+    let Some(mut host) = start_host(handler, state_lock, settings) else {
+        return;
+    };
+    // Read incoming network events.
+    listener.for_each(move |event| host.handle(event));
+}
+
+//Run a host on an in-memory network, it handles events as the network is stepped
+pub fn run_host_in(network: &MemoryNetwork, state_lock: Arc<Mutex<game::GameState>>, settings: ServerSettings) {
+    network.spawn(|net| start_host(net, state_lock, settings).map(|mut host| move |event: NodeEvent<Signal>| host.handle(event)));
 }
@@ -0,0 +1,326 @@
+//Compact position snapshots
+//Positions are rounded to fixed point, then each snapshot only carries what changed since the last one
+//the client acknowledged, bit packed. If snapshots are lost the server keeps encoding against the older
+//acknowledged one, so the client still ends up with the right positions.
+//See https://gafferongames.com/post/snapshot_compression/
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::net_common::{NetPosition, PositionMap};
+
+//Fractions of a pixel kept, 1/8 is far below anything visible
+pub const POSITION_SCALE: f32 = 8.0;
+//Snapshots kept on each side to encode or decode against
+const HISTORY: usize = 64;
+
+pub type Quantised = (i32, i32);
+//Positions of every player a client knows about, as of one snapshot
+pub type SnapshotState = BTreeMap<u8, Quantised>;
+
+pub fn quantise(pos: NetPosition) -> Quantised {
+    ((pos.x * POSITION_SCALE).round() as i32, (pos.y * POSITION_SCALE).round() as i32)
+}
+
+pub fn dequantise(q: Quantised) -> NetPosition {
+    NetPosition {
+        x: q.0 as f32 / POSITION_SCALE,
+        y: q.1 as f32 / POSITION_SCALE,
+    }
+}
+
+//Small signed numbers to small unsigned ones, 0, -1, 1, -2...
+fn zigzag(v: i32) -> u32 {
+    ((v << 1) ^ (v >> 31)) as u32
+}
+
+fn unzigzag(v: u32) -> i32 {
+    ((v >> 1) as i32) ^ -((v & 1) as i32)
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u32, //Bits used in the last byte, 8 when full
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        for i in (0..bits).rev() {
+            if self.used == 8 || self.bytes.is_empty() {
+                self.bytes.push(0);
+                self.used = 0;
+            }
+            let bit = ((value >> i) & 1) as u8;
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= bit << (7 - self.used);
+            self.used += 1;
+        }
+    }
+
+    //Numbers are mostly small, so a 2 bit size class goes in front
+    fn write_number(&mut self, value: u32) {
+        match value {
+            0..=0xF => {
+                self.write(0, 2);
+                self.write(value, 4);
+            }
+            0x10..=0xFF => {
+                self.write(1, 2);
+                self.write(value, 8);
+            }
+            0x100..=0xFFFF => {
+                self.write(2, 2);
+                self.write(value, 16);
+            }
+            _ => {
+                self.write(3, 2);
+                self.write(value, 32);
+            }
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    bit: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader { bytes, bit: 0 }
+    }
+
+    fn read(&mut self, bits: u32) -> Option<u32> {
+        let mut value = 0u32;
+        for _ in 0..bits {
+            let byte = self.bytes.get(self.bit / 8)?;
+            let bit = (byte >> (7 - self.bit % 8)) & 1;
+            value = (value << 1) | bit as u32;
+            self.bit += 1;
+        }
+        Some(value)
+    }
+
+    fn read_number(&mut self) -> Option<u32> {
+        let bits = match self.read(2)? {
+            0 => 4,
+            1 => 8,
+            2 => 16,
+            _ => 32,
+        };
+        self.read(bits)
+    }
+}
+
+//Encode state as changes from base, or in full if there's no base
+pub fn encode(base: Option<&SnapshotState>, state: &SnapshotState) -> Vec<u8> {
+    let empty = SnapshotState::new();
+    let base = base.unwrap_or(&empty);
+    let mut w = BitWriter::default();
+
+    let changed: Vec<_> = state.iter().filter(|(id, pos)| base.get(id) != Some(pos)).collect();
+    w.write_number(changed.len() as u32);
+    for (id, pos) in changed {
+        //New players are relative to 0, i.e absolute
+        let from = base.get(id).copied().unwrap_or((0, 0));
+        w.write(*id as u32, 8);
+        w.write_number(zigzag(pos.0.wrapping_sub(from.0)));
+        w.write_number(zigzag(pos.1.wrapping_sub(from.1)));
+    }
+
+    let removed: Vec<_> = base.keys().filter(|id| !state.contains_key(id)).collect();
+    w.write_number(removed.len() as u32);
+    for id in removed {
+        w.write(*id as u32, 8);
+    }
+
+    w.bytes
+}
+
+//Rebuild a state from changes against base, returns it and the ids that changed
+pub fn decode(base: Option<&SnapshotState>, data: &[u8]) -> Option<(SnapshotState, Vec<u8>)> {
+    let mut state = base.cloned().unwrap_or_default();
+    let mut r = BitReader::new(data);
+
+    //Can't be more entries than there are ids
+    let count = r.read_number()?;
+    if count > 256 {
+        return None;
+    }
+    let mut changed = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let id = r.read(8)? as u8;
+        let dx = unzigzag(r.read_number()?);
+        let dy = unzigzag(r.read_number()?);
+        let from = state.get(&id).copied().unwrap_or((0, 0));
+        state.insert(id, (from.0.wrapping_add(dx), from.1.wrapping_add(dy)));
+        changed.push(id);
+    }
+
+    let count = r.read_number()?;
+    if count > 256 {
+        return None;
+    }
+    for _ in 0..count {
+        state.remove(&(r.read(8)? as u8));
+    }
+
+    Some((state, changed))
+}
+
+//Server side record of what one client has been sent and acknowledged
+pub struct SnapshotSender {
+    next_seq: u32,
+    sent: VecDeque<(u32, SnapshotState)>,
+    acked: Option<u32>,
+}
+
+impl SnapshotSender {
+    pub fn new() -> SnapshotSender {
+        SnapshotSender {
+            next_seq: 1, //0 means no base
+            sent: VecDeque::new(),
+            acked: None,
+        }
+    }
+
+    //Client has this snapshot, so later ones can be encoded against it
+    pub fn ack(&mut self, seq: u32) {
+        if self.acked.is_some_and(|a| a >= seq) || !self.sent.iter().any(|(s, _)| *s == seq) {
+            return;
+        }
+        self.acked = Some(seq);
+        //Nothing older will be needed as a base again
+        while self.sent.front().is_some_and(|(s, _)| *s < seq) {
+            self.sent.pop_front();
+        }
+    }
+
+    //State in the most recent snapshot sent, acknowledged or not
+    pub fn last_sent(&self) -> Option<&SnapshotState> {
+        self.sent.back().map(|(_, state)| state)
+    }
+
+    //Encode the next snapshot, returns its number, the number of its base (0 if none) and the data
+    pub fn encode(&mut self, state: SnapshotState) -> (u32, u32, Vec<u8>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let base = self.acked.and_then(|a| self.sent.iter().find(|(s, _)| *s == a));
+        let data = encode(base.map(|(_, state)| state), &state);
+        let base_seq = base.map_or(0, |(s, _)| *s);
+
+        self.sent.push_back((seq, state));
+        //Client hasn't acknowledged anything in a while, start again from full snapshots
+        if self.sent.len() > HISTORY {
+            if let Some((dropped, _)) = self.sent.pop_front() {
+                if self.acked == Some(dropped) {
+                    self.acked = None;
+                }
+            }
+        }
+        (seq, base_seq, data)
+    }
+}
+
+impl Default for SnapshotSender {
+    fn default() -> SnapshotSender {
+        SnapshotSender::new()
+    }
+}
+
+//Client side record of decoded snapshots, to decode later ones against
+#[derive(Default)]
+pub struct SnapshotReceiver {
+    states: VecDeque<(u32, SnapshotState)>,
+}
+
+impl SnapshotReceiver {
+    //Decode a snapshot, returns the positions that changed or nothing if its base is unknown
+    pub fn receive(&mut self, seq: u32, base: u32, data: &[u8]) -> Option<Vec<PositionMap>> {
+        let base_state = if base == 0 {
+            None
+        } else {
+            Some(&self.states.iter().find(|(s, _)| *s == base)?.1)
+        };
+        let (state, changed) = decode(base_state, data)?;
+
+        let positions = changed
+            .iter()
+            .filter_map(|id| state.get(id).map(|q| PositionMap { id: *id, pos: dequantise(*q) }))
+            .collect();
+
+        self.states.push_back((seq, state));
+        if self.states.len() > HISTORY {
+            self.states.pop_front();
+        }
+        Some(positions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(entries: &[(u8, Quantised)]) -> SnapshotState {
+        entries.iter().copied().collect()
+    }
+
+    #[test]
+    fn full_round_trip() {
+        let full = state(&[(0, (0, 0)), (3, (-5, 17)), (200, (i32::MAX, i32::MIN)), (255, (123_456, -7))]);
+        let (decoded, mut changed) = decode(None, &encode(None, &full)).unwrap();
+        assert_eq!(decoded, full);
+        changed.sort();
+        assert_eq!(changed, vec![0, 3, 200, 255]);
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let base = state(&[(1, (800, 800)), (2, (1600, 40)), (3, (0, 0))]);
+        //1 moves, 2 stays, 3 leaves and 4 arrives
+        let next = state(&[(1, (803, 798)), (2, (1600, 40)), (4, (2400, 2400))]);
+        let data = encode(Some(&base), &next);
+        let (decoded, changed) = decode(Some(&base), &data).unwrap();
+        assert_eq!(decoded, next);
+        assert_eq!(changed, vec![1, 4]);
+        assert!(data.len() < encode(None, &next).len());
+    }
+
+    #[test]
+    fn unknown_base_falls_back_to_full() {
+        let first = state(&[(1, (8, 8))]);
+        let mut sender = SnapshotSender::new();
+        let (seq, base, _) = sender.encode(first.clone());
+        assert_eq!(base, 0);
+        sender.ack(seq);
+        let (_, base, data) = sender.encode(first.clone());
+        assert_eq!(base, seq);
+
+        //A client that never got the base can't use the delta
+        let mut receiver = SnapshotReceiver::default();
+        assert!(receiver.receive(seq + 1, base, &data).is_none());
+
+        //Once the acknowledged one is too old to keep, the server goes back to full snapshots
+        for _ in 0..HISTORY {
+            sender.encode(first.clone());
+        }
+        let (seq, base, data) = sender.encode(first);
+        assert_eq!(base, 0);
+        let positions = receiver.receive(seq, base, &data).unwrap();
+        assert_eq!(positions.len(), 1);
+        assert_eq!((positions[0].id, positions[0].pos.x, positions[0].pos.y), (1, 1.0, 1.0));
+    }
+
+    #[test]
+    fn quantisation_error_is_bounded() {
+        let max_error = 0.5 / POSITION_SCALE;
+        for i in 0..10_000 {
+            let v = i as f32 * 0.731 - 2000.0;
+            let pos = NetPosition { x: v, y: -v * 0.37 };
+            let back = dequantise(quantise(pos));
+            assert!((back.x - pos.x).abs() <= max_error, "{} came back as {}", pos.x, back.x);
+            assert!((back.y - pos.y).abs() <= max_error, "{} came back as {}", pos.y, back.y);
+        }
+    }
+}
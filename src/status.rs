@@ -0,0 +1,110 @@
+//Asking a server what it's running without joining it
+//A datagram to the game port gets a reply, like Source's A2S_INFO, so nothing shows up in game. The first query
+//only gets a challenge back, which has to be sent again with the query, see challenge.rs.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::wire;
+
+//Marks status packets, so they can't be mistaken for game datagrams
+const MAGIC: [u8; 4] = *b"MPXS";
+//Queries are resent this many times before giving up, in case one is lost
+const ATTEMPTS: u32 = 3;
+//Biggest reply that fits in a datagram
+const MAX_BYTES: u64 = 65536;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StatusPlayer {
+    pub id: u8,
+    pub name: String,
+}
+
+//What a server reports about itself
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ServerStatus {
+    pub protocol_version: u16,
+    pub build_id: String,
+    pub name: String,
+    pub map: String,
+    pub players: Vec<StatusPlayer>,
+    pub spectators: Vec<String>,
+    pub max_players: u8,
+    pub uptime_secs: u64,
+    pub password: bool, //Whether joining needs a password
+}
+
+#[derive(Serialize, Deserialize)]
+enum Status {
+    Query(u64), //Challenge the server handed out, 0 to ask for one
+    Challenge(u64),
+    Reply(ServerStatus),
+}
+
+fn encode(message: &Status) -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    data.extend(bincode::serialize(message).unwrap());
+    data
+}
+
+fn decode(data: &[u8]) -> Option<Status> {
+    let body = data.strip_prefix(&MAGIC)?;
+    wire::decode(body, MAX_BYTES).ok()
+}
+
+//Whether a datagram is a status packet at all, rather than game traffic
+pub fn is_status(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+//Challenge a query came with, if it is one
+pub fn query_challenge(data: &[u8]) -> Option<u64> {
+    match decode(data) {
+        Some(Status::Query(challenge)) => Some(challenge),
+        _ => None,
+    }
+}
+
+pub fn challenge(challenge: u64) -> Vec<u8> {
+    encode(&Status::Challenge(challenge))
+}
+
+pub fn reply(status: ServerStatus) -> Vec<u8> {
+    encode(&Status::Reply(status))
+}
+
+//Ask the server at address for its status, waiting up to timeout for each attempt
+pub fn query(address: SocketAddr, timeout: Duration) -> std::io::Result<ServerStatus> {
+    let local: IpAddr = match address {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((local, 0))?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(address)?;
+
+    let mut challenge = 0;
+    let mut buf = vec![0u8; 65536]; //Replies with many players can be bigger than one packet
+    for _ in 0..ATTEMPTS {
+        socket.send(&encode(&Status::Query(challenge)))?;
+        //Skip anything that isn't a reply, until the wait runs out
+        loop {
+            match socket.recv(&mut buf) {
+                Ok(len) => match decode(&buf[..len]) {
+                    Some(Status::Reply(status)) => return Ok(status),
+                    //Ask again straight away, which doesn't use up an attempt
+                    Some(Status::Challenge(c)) => {
+                        challenge = c;
+                        socket.send(&encode(&Status::Query(challenge)))?;
+                    }
+                    _ => {}
+                },
+                Err(err) if matches!(err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => break,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "no reply from server"))
+}
@@ -0,0 +1,145 @@
+//Fixed rate clock for the server simulation
+//Each tick is scheduled from the start time rather than from the end of the last one,
+//so the rate doesn't drift with however long each tick takes
+
+use std::time::{Duration, Instant};
+
+use crate::clock;
+
+pub const DEFAULT_TICK_RATE: u32 = 60;
+pub const MAX_TICK_RATE: u32 = 1000;
+//Falling further behind than this skips ticks instead of running them back to back
+const MAX_CATCH_UP: u32 = 5;
+//How often to report timing problems
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+//Timing problems since the last report
+#[derive(Default)]
+pub struct TickStats {
+    pub ticks: u32,
+    //Started more than a tick after they were due
+    pub late: u32,
+    pub max_late: Duration,
+    //Took longer than a tick to run
+    pub overruns: u32,
+    pub max_work: Duration,
+    //Never run because the server was too far behind
+    pub skipped: u32,
+}
+
+pub struct TickClock {
+    period: Duration,
+    start: Instant,
+    tick: u32,
+    tick_started: Instant,
+    stats: TickStats,
+    last_report: Instant,
+}
+
+impl TickClock {
+    pub fn new(rate: u32) -> TickClock {
+        let now = clock::now();
+        TickClock {
+            period: Duration::from_secs(1) / rate.clamp(1, MAX_TICK_RATE),
+            start: now,
+            tick: 0,
+            tick_started: now,
+            stats: TickStats::default(),
+            last_report: now,
+        }
+    }
+
+    fn due(&self, tick: u32) -> Instant {
+        self.start + self.period * tick
+    }
+
+    //Start the next tick, returns its number
+    pub fn begin(&mut self) -> u32 {
+        let now = clock::now();
+        self.tick += 1;
+
+        let late = now.saturating_duration_since(self.due(self.tick));
+        if late > self.period {
+            self.stats.late += 1;
+            self.stats.max_late = self.stats.max_late.max(late);
+
+            //Too far behind to catch up, drop the missed ticks so the next ones are on time
+            let behind = (late.as_nanos() / self.period.as_nanos()) as u32;
+            if behind > MAX_CATCH_UP {
+                self.tick += behind;
+                self.stats.skipped += behind;
+            }
+        }
+
+        self.stats.ticks += 1;
+        self.tick_started = now;
+        self.tick
+    }
+
+    //Finish the current tick, returns how long to wait before the next
+    pub fn end(&mut self) -> Duration {
+        let now = clock::now();
+        let work = now - self.tick_started;
+        if work > self.period {
+            self.stats.overruns += 1;
+        }
+        self.stats.max_work = self.stats.max_work.max(work);
+
+        self.due(self.tick + 1).saturating_duration_since(now)
+    }
+
+    //Stats since the last report, if it's time for one
+    pub fn report(&mut self) -> Option<TickStats> {
+        if clock::since(self.last_report) < REPORT_INTERVAL {
+            return None;
+        }
+        self.last_report = clock::now();
+        Some(std::mem::take(&mut self.stats))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn ticks_stay_on_the_fixed_step() {
+        let start = Instant::now();
+        clock::drive(Some(start));
+        let mut clock = TickClock::new(100);
+        let mut now = start;
+        for n in 1..=50 {
+            //However long each tick takes, the next is due on the same step from the start
+            now += clock.end();
+            clock::drive(Some(now));
+            assert_eq!(clock.begin(), n);
+            assert_eq!(now, start + PERIOD * n);
+            now += Duration::from_millis(n as u64 % 9);
+            clock::drive(Some(now));
+        }
+        let stats = std::mem::take(&mut clock.stats);
+        assert_eq!((stats.ticks, stats.late, stats.skipped), (50, 0, 0));
+    }
+
+    #[test]
+    fn falling_behind_catches_up_then_skips() {
+        let start = Instant::now();
+        clock::drive(Some(start));
+        let mut clock = TickClock::new(100);
+
+        //A little behind, the missed ticks run back to back
+        clock::drive(Some(start + PERIOD * 4));
+        assert_eq!(clock.begin(), 1);
+        assert_eq!(clock.end(), Duration::ZERO);
+        assert_eq!(clock.stats.skipped, 0);
+
+        //Too far behind, they're dropped and the next tick is on time
+        let now = start + PERIOD * 100;
+        clock::drive(Some(now));
+        assert_eq!(clock.begin(), 100);
+        assert_eq!(clock.stats.skipped, 98);
+        assert_eq!(clock.end(), PERIOD);
+    }
+}
@@ -0,0 +1,68 @@
+//What the host and client run over, real sockets from message-io or the in-memory network for tests
+//Either way events come in as message-io's NodeEvent, this is everything that goes the other way.
+//S is the signal type, which each side sends itself for timers.
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use message_io::network::{Endpoint, ResourceId, Transport, TransportConnect, TransportListen};
+use message_io::node::NodeHandler;
+
+pub trait Network<S>: Clone {
+    //Send on a connection, or a datagram back to wherever one from a listener came from
+    fn send(&self, endpoint: Endpoint, data: &[u8]);
+
+    //Connected (or not) comes as an event later, for UDP straight away
+    fn connect(&self, transport: TransportConnect, address: SocketAddr) -> io::Result<(Endpoint, SocketAddr)>;
+
+    fn listen(&self, transport: TransportListen, address: SocketAddr) -> io::Result<(ResourceId, SocketAddr)>;
+
+    //Whether an endpoint is for datagrams, which can be lost, duplicated or reordered on the way
+    fn is_datagram(&self, endpoint: Endpoint) -> bool;
+
+    //Close a connection or socket from this side, only the other end of a connection sees it disconnect
+    fn remove(&self, resource: ResourceId);
+
+    //Handled after any events already waiting
+    fn signal(&self, signal: S);
+
+    fn signal_after(&self, signal: S, wait: Duration);
+
+    //No more events are handled, whatever is running them returns
+    fn stop(&self);
+}
+
+impl<S: Send + 'static> Network<S> for NodeHandler<S> {
+    fn send(&self, endpoint: Endpoint, data: &[u8]) {
+        self.network().send(endpoint, data);
+    }
+
+    fn connect(&self, transport: TransportConnect, address: SocketAddr) -> io::Result<(Endpoint, SocketAddr)> {
+        self.network().connect_with(transport, address)
+    }
+
+    fn listen(&self, transport: TransportListen, address: SocketAddr) -> io::Result<(ResourceId, SocketAddr)> {
+        self.network().listen_with(transport, address)
+    }
+
+    fn is_datagram(&self, endpoint: Endpoint) -> bool {
+        endpoint.resource_id().adapter_id() == Transport::Udp.id()
+    }
+
+    fn remove(&self, resource: ResourceId) {
+        self.network().remove(resource);
+    }
+
+    fn signal(&self, signal: S) {
+        self.signals().send(signal);
+    }
+
+    fn signal_after(&self, signal: S, wait: Duration) {
+        self.signals().send_with_timer(signal, wait);
+    }
+
+    fn stop(&self) {
+        NodeHandler::stop(self);
+    }
+}
@@ -0,0 +1,150 @@
+//Server side checks on movement input from clients
+//Inputs can only move a player at walking or sprint speed, so the remaining cheat is
+//claiming more time than has really passed. Each client gets a budget of real time to spend.
+
+use std::time::Instant;
+
+use crate::clock;
+use crate::movement::{MoveInput, MAX_INPUT_DT};
+
+//Most time a client can save up, covers network jitter without allowing big bursts
+const MAX_BUDGET: f32 = 0.5;
+//Slack before over-claimed time counts as a violation, for clock and frame timing noise
+const TOLERANCE: f32 = 0.05;
+
+pub struct MoveValidator {
+    budget: f32,
+    last_refill: Instant,
+    pub violations: u32,
+}
+
+impl MoveValidator {
+    pub fn new() -> MoveValidator {
+        MoveValidator {
+            budget: 0.0,
+            last_refill: clock::now(),
+            violations: 0,
+        }
+    }
+
+    //Add the real time passed since last refill to the budget
+    pub fn refill(&mut self) {
+        let now = clock::now();
+        self.budget = (self.budget + (now - self.last_refill).as_secs_f32()).min(MAX_BUDGET);
+        self.last_refill = now;
+    }
+
+    //Return the input to simulate, clamped if it's illegal, with the reason if it was
+    pub fn check(&mut self, input: &MoveInput) -> (MoveInput, Option<String>) {
+        let mut checked = *input;
+
+        if !input.x.is_finite() || !input.y.is_finite() || !input.dt.is_finite() {
+            checked.x = 0.0;
+            checked.y = 0.0;
+            checked.dt = 0.0;
+            self.violations += 1;
+            return (checked, Some(String::from("non-finite input")));
+        }
+
+        //Long frames are clamped the same way on the client, so only negative time is suspicious
+        let mut reason = None;
+        if input.dt < 0.0 {
+            reason = Some(format!("negative input time {:.3}s", input.dt));
+        }
+        checked.dt = input.dt.clamp(0.0, MAX_INPUT_DT);
+
+        if checked.dt > self.budget + TOLERANCE {
+            reason = Some(format!(
+                "input time {:.3}s with only {:.3}s passed, possible speed hack",
+                checked.dt, self.budget
+            ));
+        }
+        //Small overdraws are allowed and paid back by later refills
+        checked.dt = checked.dt.min((self.budget + TOLERANCE).max(0.0));
+        self.budget -= checked.dt;
+
+        if reason.is_some() {
+            self.violations += 1;
+        }
+        (checked, reason)
+    }
+}
+
+impl Default for MoveValidator {
+    fn default() -> MoveValidator {
+        MoveValidator::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::movement::{self, BASE_SPEED, SPRINT_MULTIPLIER};
+    use crate::net_common::{NetBuilding, NetColour, NetPosition};
+    use std::time::Duration;
+
+    fn input(dt: f32) -> MoveInput {
+        MoveInput { seq: 0, x: 1.0, y: 0.0, sprint: true, dt }
+    }
+
+    #[test]
+    fn honest_inputs_pass() {
+        let start = Instant::now();
+        clock::drive(Some(start));
+        let mut validator = MoveValidator::new();
+        for frame in 1..=10 {
+            clock::drive(Some(start + Duration::from_millis(16 * frame)));
+            validator.refill();
+            let (checked, reason) = validator.check(&input(0.016));
+            assert_eq!((checked.dt, reason), (0.016, None));
+        }
+        assert_eq!(validator.violations, 0);
+    }
+
+    #[test]
+    fn speed_hack_is_clamped() {
+        clock::drive(Some(Instant::now()));
+        let mut validator = MoveValidator::new();
+
+        //Claiming time that never passed only gets the tolerance
+        let (checked, reason) = validator.check(&input(MAX_INPUT_DT));
+        assert_eq!(checked.dt, TOLERANCE);
+        assert!(reason.unwrap().contains("speed hack"));
+
+        //Which has to be paid back before anything more is allowed
+        let (checked, _) = validator.check(&input(MAX_INPUT_DT));
+        assert_eq!(checked.dt, 0.0);
+        let (checked, _) = validator.check(&input(-1.0));
+        assert_eq!(checked.dt, 0.0);
+        let (checked, _) = validator.check(&input(f32::NAN));
+        assert_eq!((checked.x, checked.dt), (0.0, 0.0));
+        assert_eq!(validator.violations, 4);
+    }
+
+    #[test]
+    fn long_frames_are_clamped() {
+        let start = Instant::now();
+        clock::drive(Some(start));
+        let mut validator = MoveValidator::new();
+        clock::drive(Some(start + Duration::from_secs(10)));
+        validator.refill();
+        let (checked, reason) = validator.check(&input(5.0));
+        assert_eq!((checked.dt, reason), (MAX_INPUT_DT, None));
+    }
+
+    #[test]
+    fn fast_move_stops_at_thin_wall() {
+        let wall = NetBuilding {
+            position: NetPosition { x: 20.0, y: -100.0 },
+            width: 1.0,
+            height: 200.0,
+            colour: NetColour { r: 0.0, g: 0.0, b: 0.0, a: 1.0 },
+        };
+        let from = NetPosition { x: 0.0, y: 0.0 };
+        let to = movement::apply_input(&[wall], from, &input(MAX_INPUT_DT));
+        assert!(to.x + movement::PLAYER_WIDTH <= 20.0, "went through the wall to {}", to.x);
+        //Would have gone well past it otherwise
+        let open = movement::apply_input(&[], from, &input(MAX_INPUT_DT));
+        assert_eq!(open.x, BASE_SPEED * SPRINT_MULTIPLIER * MAX_INPUT_DT);
+    }
+}
@@ -0,0 +1,171 @@
+//Decoding messages off the network, which may come from someone trying to crash or bloat whoever reads them
+//bincode believes length prefixes, so every decode here has a cap on how much it can read, and every
+//decoded command has its fields checked before anything acts on it
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+
+use crate::net_common::{
+    Commands, Datagram, NetBuilding, NetColour, NetPlayer, NetPosition, RegistrationInfo, Successor,
+};
+
+//Largest reliable message, far more than the biggest map or player list
+pub const MAX_MESSAGE_BYTES: u64 = 256 * 1024;
+//Largest datagram, a snapshot with every player in it is well under this
+pub const MAX_DATAGRAM_BYTES: u64 = 16 * 1024;
+
+pub const MAX_NAME_LEN: usize = 32;
+const MAX_BUILD_ID_LEN: usize = 64;
+const MAX_REASON_LEN: usize = 256;
+const MAX_PASSWORD_LEN: usize = 256;
+//More than a client ever resends at once
+const MAX_INPUTS: usize = 64;
+const MAX_BUILDINGS: usize = 4096;
+//One for every possible id
+const MAX_PLAYERS: usize = 256;
+
+//Same encoding as bincode::serialize, but stopping once limit bytes have been read
+pub fn decode<T: DeserializeOwned>(data: &[u8], limit: u64) -> Result<T, String> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(limit)
+        .deserialize(data)
+        .map_err(|err| err.to_string())
+}
+
+//Decode and check a reliable message
+pub fn decode_command(data: &[u8]) -> Result<Commands, String> {
+    let command = decode(data, MAX_MESSAGE_BYTES)?;
+    check_command(&command)?;
+    Ok(command)
+}
+
+//Decode and check a datagram, after decryption
+pub fn decode_datagram(data: &[u8]) -> Result<Datagram, String> {
+    let datagram: Datagram = decode(data, MAX_DATAGRAM_BYTES)?;
+    check_command(&datagram.command)?;
+    Ok(datagram)
+}
+
+//Player names are shown to everyone, so keep them short and printable
+pub fn check_name(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err(String::from("Name is empty"));
+    }
+    if name.chars().count() > MAX_NAME_LEN {
+        return Err(format!("Name is longer than {} characters", MAX_NAME_LEN));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(String::from("Name has control characters"));
+    }
+    Ok(())
+}
+
+fn check_len(what: &str, len: usize, max: usize) -> Result<(), String> {
+    if len > max {
+        return Err(format!("{} has {} entries, limit is {}", what, len, max));
+    }
+    Ok(())
+}
+
+fn check_position(pos: &NetPosition) -> Result<(), String> {
+    if !pos.x.is_finite() || !pos.y.is_finite() {
+        return Err(String::from("Position is not finite"));
+    }
+    Ok(())
+}
+
+fn check_colour(colour: &NetColour) -> Result<(), String> {
+    //Also catches NaN, which is in no range
+    if [colour.r, colour.g, colour.b, colour.a].iter().any(|c| !(0.0..=1.0).contains(c)) {
+        return Err(String::from("Colour is outside 0 to 1"));
+    }
+    Ok(())
+}
+
+fn check_player(player: &NetPlayer) -> Result<(), String> {
+    check_name(&player.name)?;
+    check_position(&player.position)?;
+    check_colour(&player.colour)
+}
+
+fn check_building(building: &NetBuilding) -> Result<(), String> {
+    check_position(&building.position)?;
+    check_colour(&building.colour)?;
+    if ![building.width, building.height].iter().all(|s| s.is_finite() && *s >= 0.0) {
+        return Err(String::from("Building size is not a finite positive number"));
+    }
+    Ok(())
+}
+
+fn check_registration(info: &RegistrationInfo) -> Result<(), String> {
+    if info.build_id.len() > MAX_BUILD_ID_LEN {
+        return Err(String::from("Build id is too long"));
+    }
+    check_name(&info.name)?;
+    check_colour(&info.colour)
+}
+
+//Settings handed on with a successor, as the successor will host with them
+fn check_successor(successor: &Successor) -> Result<(), String> {
+    check_len("Session list", successor.sessions.len(), MAX_PLAYERS)?;
+    if successor.password.as_ref().is_some_and(|p| p.len() > MAX_PASSWORD_LEN) {
+        return Err(String::from("Password is too long"));
+    }
+    let limits = &successor.rate_limits;
+    let all = [limits.total, limits.updates, limits.other, limits.queries, limits.unauthenticated];
+    if all.iter().any(|l| !(l.rate.is_finite() && l.burst.is_finite() && l.rate >= 0.0 && l.burst >= 0.0)) {
+        return Err(String::from("Rate limit is out of range"));
+    }
+    Ok(())
+}
+
+//Whether every field of a command is within what the game can ever send
+pub fn check_command(command: &Commands) -> Result<(), String> {
+    match command {
+        Commands::RegisterPlayer(info) => check_registration(info),
+        Commands::RejectRegistration(reason) | Commands::Warning(reason) | Commands::Kick(reason) => {
+            if reason.len() > MAX_REASON_LEN {
+                return Err(String::from("Reason is too long"));
+            }
+            Ok(())
+        }
+        Commands::Inputs(inputs) => {
+            check_len("Inputs", inputs.len(), MAX_INPUTS)?;
+            if inputs.iter().any(|i| !i.x.is_finite() || !i.y.is_finite() || !i.dt.is_finite()) {
+                return Err(String::from("Input is not finite"));
+            }
+            Ok(())
+        }
+        Commands::AckInput(ack) => check_position(&ack.pos),
+        Commands::SendMap(map) => {
+            check_len("Map", map.buildings.len(), MAX_BUILDINGS)?;
+            map.buildings.iter().try_for_each(check_building)
+        }
+        Commands::SendPlayerInfo(info) => {
+            check_len("Player list", info.players.len(), MAX_PLAYERS)?;
+            check_len("Reconnecting list", info.reconnecting.len(), MAX_PLAYERS)?;
+            info.players.iter().try_for_each(check_player)
+        }
+        Commands::AddPlayer(player) => check_player(player),
+        Commands::EnterInterest(p) => check_position(&p.pos),
+        Commands::Successor(Some(successor)) => check_successor(successor),
+        //Snapshot contents are checked as they're unpacked, everything else is plain numbers
+        Commands::MovedPlayers(_)
+        | Commands::RemovePlayer(_)
+        | Commands::AllowClientReady(_)
+        | Commands::ClientReady
+        | Commands::BindUdp(_)
+        | Commands::UdpBound
+        | Commands::Ping(_)
+        | Commands::Pong(_)
+        | Commands::Session(_)
+        | Commands::Reconnecting(_)
+        | Commands::Reconnected(_)
+        | Commands::LeaveInterest(_)
+        | Commands::AckSnapshot(_)
+        | Commands::Challenge(_)
+        | Commands::Successor(None)
+        | Commands::HostLeaving => Ok(()),
+    }
+}
@@ -0,0 +1,399 @@
+//Whole games of a host and clients over the in-memory network
+//Time only moves when the network runs, so every run plays out the same, and seconds of play take no time.
+
+use std::cell::Cell;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use message_io::network::{NetEvent, TransportConnect};
+use message_io::node::NodeEvent;
+
+use macroprox::client;
+use macroprox::game::{GameReadiness, GameState};
+use macroprox::maps::load_map_1;
+use macroprox::memory_network::{MemoryNetwork, MemoryNode};
+use macroprox::menu::GameSettings;
+use macroprox::movement::{self, MoveInput};
+use macroprox::replay;
+use macroprox::net_common::{Commands, NetColour, NetPlayer, NetPosition};
+use macroprox::netsim::{Conditions, SharedSim};
+use macroprox::secure::StaticKey;
+use macroprox::server::{self, ServerSettings};
+use macroprox::transport::Network;
+
+const LATENCY: Duration = Duration::from_millis(20);
+const FRAME: Duration = Duration::from_millis(16);
+const PORT: u16 = 5508;
+
+type Shared = Arc<Mutex<GameState>>;
+
+//File for one test, e.g known servers, removed after
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new() -> TempFile {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let name = format!("macroprox-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        TempFile(std::env::temp_dir().join(name))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn start_server(net: &MemoryNetwork, settings: ServerSettings) -> Shared {
+    let state = Arc::new(Mutex::new(GameState::default()));
+    load_map_1(&state);
+    state.lock().unwrap().spawn = NetPosition { x: 300.0, y: 300.0 };
+    server::run_host_in(
+        net,
+        Arc::clone(&state),
+        ServerSettings {
+            discoverable: false,
+            key: Some(StaticKey::generate()),
+            ..settings
+        },
+    );
+    state
+}
+
+//Without a player of its own
+fn dedicated() -> ServerSettings {
+    ServerSettings { host_player: false, ..Default::default() }
+}
+
+fn join(net: &MemoryNetwork, known: &TempFile, name: &str, password: &str) -> Shared {
+    let state = Arc::new(Mutex::new(GameState::default()));
+    let settings = GameSettings {
+        host: Some(SocketAddr::from(([127, 0, 0, 1], PORT))),
+        player_name: name.to_string(),
+        password: password.to_string(),
+        known_servers: known.0.clone(),
+        ..Default::default()
+    };
+    client::run_client_in(net, settings, Arc::clone(&state));
+    state
+}
+
+//Same as typing the server into the menu, for the client to look up
+fn join_named(net: &MemoryNetwork, known: &TempFile, host: &str) -> Shared {
+    let state = Arc::new(Mutex::new(GameState::default()));
+    let settings = GameSettings {
+        host_name: host.to_string(),
+        port: PORT,
+        known_servers: known.0.clone(),
+        ..Default::default()
+    };
+    client::run_client_in(net, settings, Arc::clone(&state));
+    state
+}
+
+fn is_ready(state: &Shared) -> bool {
+    matches!(state.lock().unwrap().ready, GameReadiness::Ready)
+}
+
+fn position(state: &Shared, id: u8) -> Option<(f32, f32)> {
+    state.lock().unwrap().players.get(&id).map(|p| (p.position.x, p.position.y))
+}
+
+fn own_id(state: &Shared) -> u8 {
+    state.lock().unwrap().own_player
+}
+
+//One frame of holding a direction, the same way the game moves its own player
+fn step(state: &Shared, x: f32, y: f32) {
+    let mut state = state.lock().unwrap();
+    let input = MoveInput { seq: 0, x, y, sprint: false, dt: FRAME.as_secs_f32() };
+    let own = state.own_player;
+    let Some(player) = state.players.get(&own) else {
+        return;
+    };
+    let pos = movement::apply_input(&state.buildings, player.position, &input);
+    if !state.hosting {
+        state.prediction.record(input);
+    }
+    state.players.get_mut(&own).unwrap().position = pos;
+}
+
+fn walk(net: &MemoryNetwork, state: &Shared, x: f32, y: f32, frames: u32) {
+    for _ in 0..frames {
+        step(state, x, y);
+        net.run_for(FRAME);
+    }
+}
+
+fn close(a: (f32, f32), b: (f32, f32)) -> bool {
+    (a.0 - b.0).abs() < 0.5 && (a.1 - b.1).abs() < 0.5
+}
+
+#[test]
+fn clients_join_and_see_each_other() {
+    let net = MemoryNetwork::new(LATENCY);
+    let known = TempFile::new();
+    let server = start_server(&net, dedicated());
+    let clients: Vec<Shared> = ["a", "b", "c"].iter().map(|name| join(&net, &known, name, "")).collect();
+    net.run_for(Duration::from_secs(1));
+
+    let ids: Vec<u8> = clients.iter().map(own_id).collect();
+    for (client, id) in clients.iter().zip(&ids) {
+        assert!(is_ready(client));
+        for other in &ids {
+            assert!(client.lock().unwrap().players.contains_key(other), "{} can't see {}", id, other);
+        }
+    }
+    assert_eq!(server.lock().unwrap().players.len(), 3);
+    assert!(ids[0] != ids[1] && ids[1] != ids[2] && ids[0] != ids[2]);
+}
+
+#[test]
+fn movement_reaches_server_and_other_clients() {
+    let net = MemoryNetwork::new(LATENCY);
+    let known = TempFile::new();
+    let server = start_server(&net, dedicated());
+    let a = join(&net, &known, "a", "");
+    let b = join(&net, &known, "b", "");
+    net.run_for(Duration::from_secs(1));
+
+    let id = own_id(&a);
+    let start = position(&server, id).unwrap();
+    walk(&net, &a, 1.0, 0.0, 30);
+    net.run_for(Duration::from_secs(1));
+
+    let moved = position(&a, id).unwrap();
+    assert!(moved.0 > start.0 + 10.0, "didn't move: {:?} to {:?}", start, moved);
+    assert!(close(position(&server, id).unwrap(), moved));
+    assert!(close(position(&b, id).unwrap(), moved));
+    let drawn = b.lock().unwrap().interpolation.position(id).unwrap();
+    assert!(close((drawn.x, drawn.y), moved));
+}
+
+#[test]
+fn typed_host_is_looked_up_by_the_client() {
+    let net = MemoryNetwork::new(LATENCY);
+    let known = TempFile::new();
+    start_server(&net, dedicated());
+    let found = join_named(&net, &known, "127.0.0.1");
+    let bad = join_named(&net, &known, "[::1");
+    net.run_for(Duration::from_secs(1));
+
+    assert!(is_ready(&found));
+    assert!(matches!(&bad.lock().unwrap().ready, GameReadiness::Error(e) if e.contains("]")));
+}
+
+#[test]
+fn wrong_password_is_rejected() {
+    let net = MemoryNetwork::new(LATENCY);
+    let known = TempFile::new();
+    let server = start_server(&net, ServerSettings { password: Some("secret".to_string()), ..dedicated() });
+    let wrong = join(&net, &known, "wrong", "guess");
+    let right = join(&net, &known, "right", "secret");
+    net.run_for(Duration::from_secs(1));
+
+    assert!(matches!(&wrong.lock().unwrap().ready, GameReadiness::Error(e) if e.contains("password")));
+    assert!(is_ready(&right));
+    assert_eq!(server.lock().unwrap().players.len(), 1);
+}
+
+#[test]
+fn rejection_arrives_through_simulated_latency() {
+    let net = MemoryNetwork::new(LATENCY);
+    let known = TempFile::new();
+    let netsim = SharedSim::default();
+    netsim.lock().unwrap().conditions = Conditions { latency_ms: 50, jitter_ms: 20, ..Default::default() };
+    let settings = ServerSettings { password: Some("secret".to_string()), netsim, ..dedicated() };
+    start_server(&net, settings);
+    let wrong = join(&net, &known, "wrong", "guess");
+    net.run_for(Duration::from_secs(1));
+
+    //The reason is still on its way when the server closes the connection
+    assert!(matches!(&wrong.lock().unwrap().ready, GameReadiness::Error(e) if e.contains("password")));
+}
+
+//Both clients walk about, returns where everyone ended up on the server
+fn play() -> Vec<(u8, (f32, f32))> {
+    let net = MemoryNetwork::new(LATENCY);
+    let known = TempFile::new();
+    let server = start_server(&net, dedicated());
+    let a = join(&net, &known, "a", "");
+    let b = join(&net, &known, "b", "");
+    net.run_for(Duration::from_millis(500));
+    for _ in 0..20 {
+        step(&b, 0.0, -1.0);
+        walk(&net, &a, 1.0, 1.0, 3);
+    }
+    net.run_for(Duration::from_millis(500));
+
+    let state = server.lock().unwrap();
+    let mut positions: Vec<_> = state.players.values().map(|p| (p.id, (p.position.x, p.position.y))).collect();
+    positions.sort_by_key(|(id, _)| *id);
+    positions
+}
+
+#[test]
+fn same_play_gives_same_game() {
+    let first = play();
+    assert_eq!(first.len(), 2);
+    assert_eq!(first, play());
+}
+
+#[test]
+fn client_takes_over_when_host_leaves() {
+    let net = MemoryNetwork::new(LATENCY);
+    let known = TempFile::new();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let settings = ServerSettings { host_player: true, shutdown: Arc::clone(&shutdown), ..Default::default() };
+    let host = start_server(&net, settings);
+    let me = NetPlayer {
+        position: NetPosition { x: 300.0, y: 300.0 },
+        id: 0,
+        name: "host".to_string(),
+        colour: NetColour { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+    };
+    host.lock().unwrap().players.insert(0, me);
+    host.lock().unwrap().hosting = true;
+    let a = join(&net, &known, "a", "");
+    let b = join(&net, &known, "b", "");
+    net.run_for(Duration::from_secs(1));
+    assert!(b.lock().unwrap().players.contains_key(&0));
+
+    //First to join is the successor, the other follows it to its game
+    shutdown.store(true, Ordering::Relaxed);
+    net.run_for(Duration::from_secs(3));
+    let (new_host, other) = (own_id(&a), own_id(&b));
+    assert!(a.lock().unwrap().hosting);
+    assert!(is_ready(&b));
+    assert_eq!(own_id(&b), other);
+    for state in [&a, &b] {
+        let state = state.lock().unwrap();
+        assert!(!state.players.contains_key(&0));
+        assert!(state.players.contains_key(&new_host) && state.players.contains_key(&other));
+    }
+
+    //And the game carries on under it
+    let start = position(&a, other).unwrap();
+    walk(&net, &b, 0.0, 1.0, 30);
+    net.run_for(Duration::from_secs(1));
+    assert!(position(&a, other).unwrap().1 > start.1 + 10.0);
+
+    //The new host is drawn a little in the past like before, on its clock rather than the old host's
+    walk(&net, &a, 1.0, 0.0, 30);
+    let latest = position(&b, new_host).unwrap();
+    let drawn = b.lock().unwrap().interpolation.position(new_host).unwrap();
+    assert!(drawn.x < latest.0 - 1.0, "drawn at {} with {} the latest", drawn.x, latest.0);
+}
+
+#[test]
+fn successor_keeps_host_settings_and_far_players() {
+    let net = MemoryNetwork::new(LATENCY);
+    let known = TempFile::new();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let settings = ServerSettings {
+        password: Some("secret".to_string()),
+        max_players: 3,
+        interest_radius: 1.0,
+        shutdown: Arc::clone(&shutdown),
+        ..Default::default()
+    };
+    let host = start_server(&net, settings);
+    host.lock().unwrap().hosting = true;
+    let a = join(&net, &known, "a", "secret");
+    let b = join(&net, &known, "b", "secret");
+    net.run_for(Duration::from_secs(1));
+
+    //Well out of a's area of interest
+    let other = own_id(&b);
+    walk(&net, &b, 0.0, 1.0, 30);
+    net.run_for(Duration::from_secs(1));
+    let far = position(&b, other).unwrap();
+    shutdown.store(true, Ordering::Relaxed);
+    net.run_for(Duration::from_secs(3));
+    assert!(a.lock().unwrap().hosting);
+    assert!(close(position(&a, other).unwrap(), far));
+    assert!(close(position(&b, other).unwrap(), far));
+
+    //Same password, and room for just one more. Newcomers haven't seen the old host's key
+    let known = TempFile::new();
+    let wrong = join(&net, &known, "wrong", "guess");
+    let right = join(&net, &known, "right", "secret");
+    net.run_for(Duration::from_secs(1));
+    let extra = join(&net, &known, "extra", "secret");
+    net.run_for(Duration::from_secs(1));
+    assert!(matches!(&wrong.lock().unwrap().ready, GameReadiness::Error(e) if e.contains("password")));
+    assert!(is_ready(&right));
+    assert!(matches!(&extra.lock().unwrap().ready, GameReadiness::Error(e) if e.contains("full")));
+}
+
+#[test]
+fn recordings_leave_out_secrets() {
+    let net = MemoryNetwork::new(LATENCY);
+    let known = TempFile::new();
+    let recording = TempFile::new();
+    let settings = ServerSettings {
+        password: Some("secret".to_string()),
+        record: Some(recording.0.clone()),
+        ..dedicated()
+    };
+    start_server(&net, settings);
+    let client = join(&net, &known, "a", "secret");
+    net.run_for(Duration::from_secs(3)); //Recordings are flushed every heartbeat
+    assert!(is_ready(&client));
+
+    let records = replay::load(&recording.0).unwrap();
+    let mut registered = false;
+    for record in &records {
+        match &record.command {
+            Commands::RegisterPlayer(info) => {
+                registered = true;
+                assert_eq!(info.password, Some([0; 32]));
+            }
+            Commands::Challenge(token) | Commands::Session(token) | Commands::BindUdp(token) => {
+                assert_eq!(*token, 0, "{} recorded", record.command.name());
+            }
+            _ => {}
+        }
+    }
+    assert!(registered);
+}
+
+#[test]
+fn replayed_bind_from_elsewhere_is_ignored() {
+    let net = MemoryNetwork::new(LATENCY);
+    let known = TempFile::new();
+    let server = start_server(&net, dedicated());
+    net.capture(PORT);
+    let client = join(&net, &known, "a", "");
+    net.run_for(Duration::from_secs(1));
+    assert!(is_ready(&client));
+    //The client binds its UDP address before sending anything else over it
+    let bind = net.captured(PORT).into_iter().next().unwrap();
+
+    //Someone else sends the same bind from their own address
+    let heard = Rc::new(Cell::new(false));
+    let attacker_heard = Rc::clone(&heard);
+    net.spawn(move |node: MemoryNode<()>| {
+        let server = SocketAddr::from(([127, 0, 0, 1], PORT));
+        let (udp, _) = node.connect(TransportConnect::Udp(Default::default()), server).ok()?;
+        node.send(udp, &bind);
+        Some(move |event: NodeEvent<()>| {
+            if let NodeEvent::Network(NetEvent::Message(..)) = event {
+                attacker_heard.set(true);
+            }
+        })
+    });
+    net.run_for(Duration::from_secs(1));
+    assert!(!heard.get(), "server answered the replayed bind");
+
+    //The client's inputs still come from the address the server has bound
+    let id = own_id(&client);
+    let start = position(&server, id).unwrap();
+    walk(&net, &client, 1.0, 0.0, 30);
+    net.run_for(Duration::from_secs(1));
+    assert!(position(&server, id).unwrap().0 > start.0 + 10.0);
+}